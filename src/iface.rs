@@ -1,21 +1,36 @@
+use crate::protocol::congestion;
 use crate::protocol::Action;
-use crate::protocol::TCB;
+use crate::protocol::{IssGenerator, TCB};
 use crate::stream::TcpListener;
-use crate::stream::{Acm, SocketPair};
+use crate::stream::{
+    Acm, Admission, Backlog, ListenerState, Overflow, SocketPair, SynCookies, TcpStream,
+};
+use crate::util;
 use log::{debug, error, info};
-use nix;
-use std::collections::{hash_map::Entry, VecDeque};
+use std::collections::hash_map::Entry;
 use std::io;
+use std::net::Ipv4Addr;
 use std::thread;
-use tun_tap;
+use std::time;
+
+/// The address of our side. run.sh gives 192.168.0.1 to the kernel side of the tun device.
+pub const DEFAULT_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+/// The MTU of the tun device, which is the largest IP packet we send or receive.
+pub const MTU: usize = 1500;
 
 pub struct Interface {
     jh: Option<thread::JoinHandle<io::Result<()>>>,
     m: Option<Acm>,
+    addr: Ipv4Addr,
 }
 
 impl Interface {
     pub fn new(ifacename: &str) -> io::Result<Self> {
+        Self::with_addr(ifacename, DEFAULT_ADDR)
+    }
+
+    /// creates an interface whose active opens use `addr` as the local address.
+    pub fn with_addr(ifacename: &str, addr: Ipv4Addr) -> io::Result<Self> {
         info!("Interface: created new interface");
         let nic = tun_tap::Iface::without_packet_info(ifacename, tun_tap::Mode::Tun)
             .expect("Failed to create interface");
@@ -29,6 +44,7 @@ impl Interface {
         Ok(Self {
             jh: Some(jh),
             m: Some(acm),
+            addr,
         })
     }
 }
@@ -44,11 +60,19 @@ impl Drop for Interface {
 }
 impl Interface {
     pub fn bind(&mut self, port: u16) -> io::Result<TcpListener> {
+        self.bind_with_backlog(port, Backlog::default())
+    }
+
+    /// binds `port` with the limits of its SYN queue and accept queue.
+    pub fn bind_with_backlog(&mut self, port: u16, backlog: Backlog) -> io::Result<TcpListener> {
         // let mut cm = self.m.as_mut().unwrap();
         let mut cm = self.m.as_mut().unwrap().manager.lock().unwrap();
-        match cm.pending.entry(port) {
+        match cm.listeners.entry(port) {
             Entry::Vacant(v) => {
-                v.insert(VecDeque::new());
+                v.insert(ListenerState {
+                    backlog,
+                    ..Default::default()
+                });
             }
             Entry::Occupied(_) => {
                 return Err(io::Error::new(
@@ -63,46 +87,133 @@ impl Interface {
             m: self.m.clone().unwrap(),
         })
     }
+
+    /// sets how many challenge ACKs of RFC 5961 the connections of this interface send per
+    /// second in total, 0 turns them off.
+    pub fn set_challenge_ack_limit(&mut self, limit: u32) {
+        let mut cm = self.m.as_ref().unwrap().manager.lock().unwrap();
+        cm.challenge_acks.set_limit(limit);
+    }
+
+    /// sets the maximum segment lifetime of the connections opened from now on, TIME-WAIT lasts
+    /// twice as long.
+    pub fn set_msl(&mut self, msl: time::Duration) {
+        let mut cm = self.m.as_ref().unwrap().manager.lock().unwrap();
+        cm.config.msl = msl;
+    }
+
+    /// sets the receive buffer size of the connections opened from now on.
+    pub fn set_recv_buffer(&mut self, size: usize) {
+        let mut cm = self.m.as_ref().unwrap().manager.lock().unwrap();
+        cm.config.recv_buffer = size;
+    }
+
+    /// sets the size the receive buffers of the connections opened from now on may grow to by
+    /// auto-tuning, None turns auto-tuning off.
+    pub fn set_recv_buffer_max(&mut self, max: Option<usize>) {
+        let mut cm = self.m.as_ref().unwrap().manager.lock().unwrap();
+        cm.config.recv_buffer_max = max;
+    }
+
+    /// Active OPEN. An ephemeral port is picked for the connection, and this function blocks
+    /// until the connection is established or failed.
+    pub fn connect(&mut self, remote: (Ipv4Addr, u16)) -> io::Result<TcpStream> {
+        let acm = self.m.as_ref().unwrap();
+        let mut cm = acm.manager.lock().unwrap();
+        let port = cm.ephemeral_port(self.addr, remote).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "no ephemeral port available",
+            )
+        })?;
+        let sp = SocketPair {
+            src: remote,
+            dst: (self.addr, port),
+        };
+        cm.aborted.remove(&sp);
+        let iss = cm.iss.generate(sp.dst, sp.src);
+        let mut c = TCB::connect(sp.dst, sp.src, iss, cm.config.recv_buffer);
+        cm.config
+            .configure(&mut c, congestion::Algorithm::default());
+        cm.connections.insert(sp, c);
+        info!("Interface: connecting {:?}", sp);
+
+        loop {
+            cm = acm.estab_notifier.wait(cm).unwrap();
+            match cm.connections.get(&sp) {
+                Some(c) if c.is_connecting() => continue,
+                Some(_) => return Ok(TcpStream::new(sp, acm.clone())),
+                None => {
+                    let kind = cm
+                        .aborted
+                        .remove(&sp)
+                        .map_or(io::ErrorKind::ConnectionAborted, |(kind, _)| kind);
+                    return Err(io::Error::new(kind, "failed to connect"));
+                }
+            }
+        }
+    }
 }
 
+/// the interval between two ticks of the connections, which is also the poll timeout.
+const TICK: time::Duration = time::Duration::from_millis(10);
+
 /// This function is initialized by the accept() method of Interface. It is a loop
 /// for writing and reading.
 /// We use epoll for incoming data, reading will be waked up if the POLLIN fd is
-/// positive. If there's no incoming data, on_tick will be waked up for writing.
+/// positive. If there's no incoming data, on_tick will be waked up for writing. on_tick is also
+/// called when packets keep arriving for a whole tick, so the timers and the pacing go on.
 fn packet_loop(mut nic: tun_tap::Iface, acm: Acm) -> io::Result<()> {
     info!("packet loop begins!");
-    let mut buf = [0u8; 1500];
+    let mut buf = [0u8; MTU];
     let mut pending_remove: Vec<SocketPair> = vec![];
+    let mut last_tick = time::Instant::now();
     loop {
         use std::os::unix::io::AsRawFd;
         let mut pfd = [nix::poll::PollFd::new(
             nic.as_raw_fd(),
             nix::poll::PollFlags::POLLIN,
         )];
-        let n = nix::poll::poll(&mut pfd[..], 10).unwrap();
+        let n = nix::poll::poll(&mut pfd[..], TICK.as_millis() as i32).unwrap();
 
         let mut cm_guard = acm.manager.lock().unwrap();
+        let removed = !pending_remove.is_empty();
         while let Some(k) = pending_remove.pop() {
-            cm_guard.connections.remove(&k);
+            cm_guard.remove(&k);
             info!("connection {:?} removed", &k);
         }
         drop(cm_guard);
+        if removed {
+            acm.estab_notifier.notify_all();
+            // the readers of an aborted connection get the error
+            acm.reading_notifier.notify_all();
+        }
 
-        if n == 0 {
+        if n == 0 || last_tick.elapsed() >= TICK {
+            last_tick = time::Instant::now();
             let mut cm_guard = acm.manager.lock().unwrap();
 
             for (k, v) in cm_guard.connections.iter_mut() {
                 let act = v.on_tick(&mut nic).unwrap();
                 if let Action::Close = act {
-                    pending_remove.push(k.clone());
+                    pending_remove.push(*k);
                 };
             }
-            continue;
+            for sp in cm_guard.expired_half_open() {
+                info!("half-open connection {:?} timed out", sp);
+                if let Some(c) = cm_guard.connections.get_mut(&sp) {
+                    c.send_rst(&mut nic).unwrap();
+                }
+                pending_remove.push(sp);
+            }
+            if n == 0 {
+                continue;
+            }
         }
 
         assert_eq!(n, 1);
         // FIXME: there must set a block to simulate latency in current implementation
-        thread::sleep(std::time::Duration::from_millis(2));
+        thread::sleep(time::Duration::from_millis(2));
 
         let buf_len = nic.recv(&mut buf[..])?;
         // let's ignore non-IP packets
@@ -145,17 +256,122 @@ fn packet_loop(mut nic: tun_tap::Iface, acm: Acm) -> io::Result<()> {
                             .expect("failed to get lock in packet_loop");
 
                         let cm = &mut *cm_guard;
+                        // RFC 6191: a new SYN may take over a pair in TIME-WAIT, the old
+                        // incarnation is dropped and the SYN is handled like any other one.
+                        let mut reopen_iss = None;
+                        if let Some(c) = cm
+                            .connections
+                            .get(&sp)
+                            .filter(|_| cm.listeners.contains_key(&local_port))
+                        {
+                            reopen_iss = c.reopen(&tcp_header);
+                            if reopen_iss.is_some() {
+                                info!("connection {:?} reopened from TIME-WAIT", sp);
+                                cm.connections.remove(&sp);
+                            }
+                        }
+                        let admission = if tcp_header.syn()
+                            && !tcp_header.ack()
+                            && !cm.connections.contains_key(&sp)
+                        {
+                            cm.admit(local_port)
+                        } else {
+                            Admission::Accept
+                        };
+                        // only the ACK of a SYN cookie, or the one which completes a handshake,
+                        // needs room in the accept queue, which is not counted for the others.
+                        let bare_ack = tcp_header.ack() && !tcp_header.syn() && !tcp_header.rst();
+                        let accept_full = bare_ack
+                            && cm.connections.get(&sp).is_none_or(|c| c.is_connecting())
+                            && cm.accept_queue_full(local_port);
                         let act = match cm.connections.entry(sp) {
                             //new connection comes as vacant
                             Entry::Vacant(con) => {
-                                if let Some(pending) = cm.pending.get_mut(&local_port) {
-                                    if let Some(c) =
-                                        TCB::new_connection(&mut nic, ip_header, tcp_header)
-                                            .unwrap()
-                                    {
+                                if let Some(listener) = cm.listeners.get_mut(&local_port) {
+                                    let iss = match (cm.iss.generate(sp.dst, sp.src), reopen_iss) {
+                                        (iss, Some(min)) if util::lt(iss, min) => min,
+                                        (iss, _) => iss,
+                                    };
+                                    let data_start =
+                                        ip_header.ihl() as usize * 4 + tcp_header.slice().len();
+                                    if admission == Admission::Cookie {
+                                        debug!("SYN cookie for {:?}", sp);
+                                        let cookie = cm.iss.cookie(
+                                            sp.dst,
+                                            sp.src,
+                                            tcp_header.sequence_number(),
+                                            syn_mss(&tcp_header),
+                                        );
+                                        TCB::send_syn_cookie(
+                                            &mut nic,
+                                            ip_header,
+                                            tcp_header,
+                                            cookie,
+                                            cm.config.recv_buffer,
+                                        )
+                                        .unwrap();
+                                        Action::Continue
+                                    } else if let Admission::Overflow(policy) = admission {
+                                        info!("SYN queue of port {} overflows", local_port);
+                                        on_overflow(
+                                            &mut nic,
+                                            policy,
+                                            &ip_header,
+                                            &tcp_header,
+                                            buf_len - data_start,
+                                        );
+                                        Action::Continue
+                                    } else if let Some(mss) = syn_cookie_ack(
+                                        &cm.iss,
+                                        listener.syn_cookies,
+                                        sp,
+                                        &tcp_header,
+                                    ) {
+                                        if accept_full {
+                                            info!("accept queue of port {} overflows", local_port);
+                                            listener.stats.accept_overflows += 1;
+                                            on_overflow(
+                                                &mut nic,
+                                                listener.backlog.overflow,
+                                                &ip_header,
+                                                &tcp_header,
+                                                buf_len - data_start,
+                                            );
+                                            continue;
+                                        }
+                                        info!("connection {:?} rebuilt from SYN cookie", sp);
+                                        let mut c = TCB::from_cookie(
+                                            &ip_header,
+                                            &tcp_header,
+                                            mss,
+                                            cm.config.recv_buffer,
+                                        );
+                                        cm.config.configure(&mut c, listener.congestion);
+                                        if data_start < buf_len {
+                                            c.on_segment(
+                                                &mut nic,
+                                                tcp_header,
+                                                &buf[data_start..buf_len],
+                                                &mut cm.challenge_acks,
+                                            )
+                                            .unwrap();
+                                        }
+                                        cm.aborted.remove(&sp);
+                                        con.insert(c);
+                                        listener.pending.push_back((sp, time::Instant::now()));
+                                        Action::New
+                                    } else if let Some(mut c) = TCB::new_connection(
+                                        ip_header,
+                                        tcp_header,
+                                        iss,
+                                        cm.config.recv_buffer,
+                                    ) {
                                         info!("new connection into pending");
+                                        cm.config.configure(&mut c, listener.congestion);
+                                        c.send_synack(&mut nic).unwrap();
+                                        cm.aborted.remove(&sp);
                                         con.insert(c);
-                                        pending.push_back(sp);
+                                        listener.pending.push_back((sp, time::Instant::now()));
                                         Action::New
                                     } else {
                                         // TODO: recovery from old connection
@@ -171,19 +387,38 @@ fn packet_loop(mut nic: tun_tap::Iface, acm: Acm) -> io::Result<()> {
                             // Existed connections comes into occupied
                             Entry::Occupied(mut con) => {
                                 debug!("packet arrives");
-                                let data_start = ip_header.ihl() as usize * 4
-                                    + tcp_header.slice().len() as usize;
-                                let act = con
-                                    .get_mut()
-                                    .on_segment(&mut nic, tcp_header, &buf[data_start..buf_len])
-                                    .unwrap();
-                                act
+                                let data_start =
+                                    ip_header.ihl() as usize * 4 + tcp_header.slice().len();
+                                let c = con.get_mut();
+                                let handshake_ack = c.is_connecting() && bare_ack;
+                                match cm.listeners.get_mut(&local_port) {
+                                    // the handshake completes while there is no room to accept it
+                                    Some(listener) if accept_full && handshake_ack => {
+                                        info!("accept queue of port {} overflows", local_port);
+                                        listener.stats.accept_overflows += 1;
+                                        match listener.backlog.overflow {
+                                            Overflow::Drop => Action::Continue,
+                                            Overflow::Reset => {
+                                                c.send_rst(&mut nic).unwrap();
+                                                Action::Close
+                                            }
+                                        }
+                                    }
+                                    _ => c
+                                        .on_segment(
+                                            &mut nic,
+                                            tcp_header,
+                                            &buf[data_start..buf_len],
+                                            &mut cm.challenge_acks,
+                                        )
+                                        .unwrap(),
+                                }
                             }
                         };
                         // cm must be dropped before notify_all.
                         // TODO: delete READ from enum
                         match act {
-                            Action::New => {
+                            Action::New | Action::Estab => {
                                 drop(cm_guard);
                                 acm.estab_notifier.notify_all()
                             }
@@ -195,7 +430,9 @@ fn packet_loop(mut nic: tun_tap::Iface, acm: Acm) -> io::Result<()> {
                                 continue;
                             }
                             Action::Close => {
-                                cm.connections.remove(&sp);
+                                cm.remove(&sp);
+                                drop(cm_guard);
+                                acm.estab_notifier.notify_all();
                             }
                         }
                     }
@@ -210,3 +447,46 @@ fn packet_loop(mut nic: tun_tap::Iface, acm: Acm) -> io::Result<()> {
         }
     }
 }
+
+/// applies the overflow policy of a listener to a segment which does not fit into its queues.
+fn on_overflow(
+    nic: &mut tun_tap::Iface,
+    policy: Overflow,
+    ip_header: &etherparse::Ipv4HeaderSlice,
+    tcp_header: &etherparse::TcpHeaderSlice,
+    data_len: usize,
+) {
+    if policy == Overflow::Reset {
+        TCB::send_reset(nic, ip_header, tcp_header, data_len).unwrap();
+    }
+}
+
+/// the MSS option of a SYN, 536 if there is none.
+fn syn_mss(tcp_header: &etherparse::TcpHeaderSlice) -> u16 {
+    tcp_header
+        .options_iterator()
+        .find_map(|option| match option {
+            Ok(etherparse::TcpOptionElement::MaximumSegmentSize(mss)) => Some(mss),
+            _ => None,
+        })
+        .unwrap_or(congestion::DEFAULT_MSS as u16)
+}
+
+/// checks whether a segment for which there is no connection is the final ACK of a handshake
+/// answered with a SYN cookie, and returns the MSS in the cookie.
+fn syn_cookie_ack(
+    iss: &IssGenerator,
+    mode: SynCookies,
+    sp: SocketPair,
+    tcp_header: &etherparse::TcpHeaderSlice,
+) -> Option<u16> {
+    if mode == SynCookies::Off || !tcp_header.ack() || tcp_header.syn() || tcp_header.rst() {
+        return None;
+    }
+    iss.check_cookie(
+        sp.dst,
+        sp.src,
+        tcp_header.sequence_number().wrapping_sub(1),
+        tcp_header.acknowledgment_number().wrapping_sub(1),
+    )
+}
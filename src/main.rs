@@ -1,15 +1,14 @@
 extern crate crossbeam;
 extern crate crossbeam_channel;
-use log::{debug, info};
+use log::info;
 use std::io;
 use std::io::{BufRead, Read, Write};
 use std::thread;
 use std::time;
 use tcpm::iface;
-use tcpm::util;
 
 fn main() -> io::Result<()> {
-    // util::logging("debug");
+    // tcpm::util::logging("debug");
     let (tx, rx) = crossbeam_channel::unbounded();
     thread::spawn(move || loop {
         let mut buffer = String::new();
@@ -27,8 +26,10 @@ fn main() -> io::Result<()> {
         let mut s = stream.clone();
         let rx = rx.clone();
         info!("Main: Got connection!");
+        // the echoes are small and interactive, they should not wait for the ACKs.
+        stream.set_nodelay(true)?;
         thread::spawn(move || {
-            stream.write(b"hello, world\n").unwrap();
+            stream.write_all(b"hello, world\n").unwrap();
             loop {
                 let mut buf = [0; 1024];
                 let n = stream.read(&mut buf[..]).unwrap();
@@ -36,24 +37,24 @@ fn main() -> io::Result<()> {
                 if n == 0 {
                     info!("Main: No more incoming data");
                     break;
-                } else if n > 1 {
+                } else {
                     let msg = String::from_utf8(buf[..n].to_vec()).unwrap();
                     println!(">>> {}", msg.trim());
                     let mut ech = String::from("echo > ");
                     ech.push_str(&msg);
-                    stream.write(ech.as_bytes()).unwrap();
+                    stream.write_all(ech.as_bytes()).unwrap();
                 }
             }
             stream.shutdown().unwrap();
         });
         thread::spawn(move || loop {
             if let Ok(msg) = rx.try_recv() {
-                if let Err(_) = s.write(msg.as_bytes()) {
+                if s.write_all(msg.as_bytes()).is_err() {
                     break;
                 };
             }
             thread::sleep(time::Duration::from_secs_f64(0.1));
         });
     }
-    return Ok(());
+    Ok(())
 }
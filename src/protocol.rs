@@ -1,11 +1,23 @@
+use crate::iface::MTU;
 use crate::util;
-use log::debug;
-use std::collections::{BTreeMap, VecDeque};
+use congestion::CongestionControl;
+use etherparse::TcpOptionElement;
+use log::{debug, info};
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::BuildHasher;
 use std::io;
 use std::io::Write;
+use std::net::Ipv4Addr;
 use std::time;
 use tun_tap::Iface;
 
+mod autotune;
+pub mod congestion;
+mod options;
+mod sack;
+mod urgent;
+
 pub struct TCB {
     state: State,
     send: SendSequenceSpace,
@@ -17,18 +29,546 @@ pub struct TCB {
     // the sequence number when FIN is sent.
     closed_at: Option<u32>,
     timers: Timers,
+    // the segments which have been sent but not fully acknowledged, in order of sequence number.
+    retransmission: VecDeque<Segment>,
+    cc: Box<dyn CongestionControl>,
+    algorithm: congestion::Algorithm,
+    // the largest amount of data and options in a segment we send, RFC 9293 3.7.1
+    mss: usize,
+    delivery: congestion::Delivery,
+    pacer: Pacer,
+    // the number of duplicate ACKs in a row
+    dup_acks: u32,
+    // the bytes which may be sent beyond cwnd on the first two duplicate ACKs, RFC 3042
+    limited_transmit: usize,
+    // SND.NXT when the retransmission timer expired, the data below it is retransmitted as cwnd
+    // allows until it is acknowledged, RFC 6582 4
+    rto_recover: Option<u32>,
+    // whether both sides use SACK. An active open offers it until the SYN of the peer arrives.
+    sack_permitted: bool,
+    timestamps: options::Timestamps,
+    // whether both sides scale their windows. It is offered until the SYN of the peer arrives
+    // without the option.
+    window_scaling: bool,
+    // the maximum segment lifetime, TIME-WAIT lasts 2 * MSL
+    msl: time::Duration,
+    delayed_ack: DelayedAck,
+    // Nagle's algorithm is off
+    nodelay: bool,
+    keepalive: Option<Keepalive>,
+    // when the last segment arrived
+    last_recv: time::Instant,
+    // the keepalive probes sent since then
+    keepalive_probes: u32,
+    // the window probes sent since then
+    window_probes: u32,
+    // the retransmission thresholds R1 and R2 of RFC 1122 4.2.3.5
+    r1: u32,
+    r2: u32,
+    // how long sent data may stay unacknowledged before the connection is aborted
+    user_timeout: Option<time::Duration>,
+    // when SND.UNA last advanced, or when the data in flight was sent if there was none
+    last_progress: time::Instant,
+    // the retransmissions have passed R1
+    trouble: bool,
+    // the sequence number following the urgent data we send, until it is acknowledged
+    snd_up: Option<u32>,
+    // the most bytes outgoing may hold
+    send_buffer: usize,
+    // the size of the receive buffer, which holds incoming
+    recv_buffer: usize,
+    // the application has read enough to open the window, the peer should be told
+    window_update: bool,
+    autotune: autotune::Autotune,
+    urgent: urgent::Receiver,
+    // whether the segment being sent is a window probe, which carries one byte whatever the
+    // windows are
+    window_probe: bool,
+    sack: sack::Receiver,
+    scoreboard: sack::Scoreboard,
 
     // stores received buffer which is waiting for reading
     pub(crate) incoming: VecDeque<u8>,
+    // stores received segments which arrived out of order
+    assembler: Assembler,
 
     // stores the buffer to be sent, including unacked buffer
     pub(crate) outgoing: VecDeque<u8>,
+
+    // the reason why this connection was torn down, reported to the user.
+    pub(crate) error: Option<io::ErrorKind>,
 }
 
+/// the length of the IP and TCP headers without options
+const HEADERS_LEN: usize = 40;
+/// the MSS we advertise, which is the largest segment the interface can take
+const LOCAL_MSS: usize = MTU - HEADERS_LEN;
+/// the smallest MSS we take from a peer, as Linux does. A tiny MSS leaves no room for data
+/// once the options are taken out.
+const MIN_MSS: usize = 88;
+
+/// the default size of the send buffer, which holds the unacknowledged data as well. It keeps
+/// a scaled window of a few hundred KiB filled.
+pub const DEFAULT_SEND_BUFFER: usize = 1 << 20;
+/// the default size of the receive buffer, which bounds the receive window
+pub const DEFAULT_RECV_BUFFER: usize = 1 << 20;
+/// the largest receive buffer, our window scale is chosen for it
+const MAX_RECV_BUFFER: usize = 16 << 20;
+/// the default size the receive buffer may grow to by auto-tuning
+pub const DEFAULT_RECV_BUFFER_MAX: usize = 6 << 20;
+/// the largest window scale, RFC 7323 2.3
+const MAX_WINDOW_SHIFT: u8 = 14;
+
+/// the number of duplicate ACKs which triggers a fast retransmit, RFC 5681 3.2
+const DUP_ACK_THRESHOLD: u32 = 3;
+
+/// how many times a SYN is retransmitted before the active open gives up.
+const SYN_RETRIES: u32 = 5;
+/// after this many retransmissions of a segment the path is reported in trouble, RFC 1122
+/// 4.2.3.5
+pub const DEFAULT_R1: u32 = 3;
+/// after this many retransmissions of a segment the connection is aborted, the default of Linux
+pub const DEFAULT_R2: u32 = 15;
+
+/// the RTO before any round trip time is measured, RFC 6298 (2.1)
+const INITIAL_RTO: time::Duration = time::Duration::from_secs(1);
+/// the RTO after the SYN has been retransmitted, RFC 6298 (5.7)
+const SYN_RETRANSMITTED_RTO: time::Duration = time::Duration::from_secs(3);
+const MIN_RTO: time::Duration = time::Duration::from_secs(1);
+const MAX_RTO: time::Duration = time::Duration::from_secs(60);
+/// the maximum segment lifetime, TIME-WAIT lasts twice as long. RFC 793 suggests 2 minutes, this
+/// is the 60 seconds TIME-WAIT of Linux.
+pub const DEFAULT_MSL: time::Duration = time::Duration::from_secs(30);
+/// an ISS above the SND.NXT of the previous incarnation is at least this far from it, as Linux
+/// does, so the new connection is not confused with the old one.
+const REOPEN_ISS_GAP: u32 = 65535 + 2;
+/// clock granularity, which is the poll timeout of packet_loop
+const CLOCK_GRANULARITY: time::Duration = time::Duration::from_millis(10);
+
+/// The retransmission timer of RFC 6298.
 pub struct Timers {
-    send_times: BTreeMap<u32, time::Instant>,
-    srtt: f64,
+    // smoothed round trip time, None before the first measurement.
+    srtt: Option<time::Duration>,
+    // round trip time variation
+    rttvar: time::Duration,
+    // retransmission timeout, the exponential backoff is included.
+    rto: time::Duration,
+    // when the retransmission timer expires, None if the timer is off.
+    expires: Option<time::Instant>,
+    // when TIME-WAIT was entered or restarted.
+    time_wait: Option<time::Instant>,
+    // when the persist timer expires, None if the timer is off.
+    persist: Option<time::Instant>,
+    // the number of window probes sent since the window closed
+    probes: u32,
+}
+impl Default for Timers {
+    fn default() -> Self {
+        Self {
+            srtt: None,
+            rttvar: time::Duration::ZERO,
+            rto: INITIAL_RTO,
+            expires: None,
+            time_wait: None,
+            persist: None,
+            probes: 0,
+        }
+    }
+}
+impl Timers {
+    /// updates SRTT, RTTVAR and RTO with a round trip time measurement, RFC 6298 (2.2) and (2.3)
+    fn sample(&mut self, rtt: time::Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                // RTTVAR <- (1 - beta) * RTTVAR + beta * |SRTT - R'|, beta = 1/4
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                // SRTT <- (1 - alpha) * SRTT + alpha * R', alpha = 1/8
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap();
+        self.rto = srtt + std::cmp::max(CLOCK_GRANULARITY, self.rttvar * 4);
+        self.rto = self.rto.clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// starts the timer if it is not running, RFC 6298 (5.1)
+    fn start(&mut self) {
+        if self.expires.is_none() {
+            self.restart();
+        }
+    }
+
+    /// (re)starts the timer so that it will expire after RTO
+    fn restart(&mut self) {
+        self.expires = Some(time::Instant::now() + self.rto);
+    }
+
+    fn stop(&mut self) {
+        self.expires = None;
+    }
+
+    fn expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| time::Instant::now() >= expires)
+    }
+
+    /// doubles the RTO when the timer expires, RFC 6298 (5.5)
+    fn backoff(&mut self) {
+        self.rto = std::cmp::min(self.rto * 2, MAX_RTO);
+    }
+
+    /// the interval between two window probes, which starts at RTO and doubles after each probe.
+    fn persist_interval(&self) -> time::Duration {
+        let backoff = 1 << std::cmp::min(self.probes, 16);
+        (self.rto * backoff).clamp(MIN_RTO, MAX_RTO)
+    }
+
+    /// starts the persist timer if it is not running.
+    fn start_persist(&mut self) {
+        if self.persist.is_none() {
+            self.persist = Some(time::Instant::now() + self.persist_interval());
+        }
+    }
+
+    fn persist_expired(&self) -> bool {
+        self.persist
+            .is_some_and(|expires| time::Instant::now() >= expires)
+    }
+
+    /// brings the running timers forward so that they expire at `deadline` at the latest.
+    fn clamp(&mut self, deadline: time::Instant) {
+        for timer in [&mut self.expires, &mut self.persist].into_iter().flatten() {
+            *timer = std::cmp::min(*timer, deadline);
+        }
+    }
+
+    /// backs off the persist timer after a probe.
+    fn on_probe(&mut self) {
+        self.probes += 1;
+        self.persist = Some(time::Instant::now() + self.persist_interval());
+    }
+
+    /// stops the persist timer, returns whether it was running.
+    fn stop_persist(&mut self) -> bool {
+        self.probes = 0;
+        self.persist.take().is_some()
+    }
+}
+
+/// Initial sequence numbers of RFC 6528: ISN = M + F(localip, localport, remoteip, remoteport,
+/// secretkey), where M is a timer ticking every 4 microseconds and F is SipHash keyed with a
+/// random secret.
+pub struct IssGenerator {
+    key: RandomState,
+    epoch: time::Instant,
+}
+
+impl Default for IssGenerator {
+    fn default() -> Self {
+        Self {
+            key: RandomState::new(),
+            epoch: time::Instant::now(),
+        }
+    }
+}
+
+/// the MSS values a SYN cookie can carry, the index of one of them is encoded in the cookie
+const COOKIE_MSS: [u16; 4] = [536, 1220, 1440, 1460];
+/// the time counter of SYN cookies ticks every 64 seconds
+const COOKIE_PERIOD: time::Duration = time::Duration::from_secs(64);
+/// a cookie is accepted for this many ticks of the counter after it was sent
+const COOKIE_MAX_AGE: u32 = 2;
+
+impl IssGenerator {
+    pub fn generate(&self, local: (Ipv4Addr, u16), remote: (Ipv4Addr, u16)) -> u32 {
+        let m = (self.epoch.elapsed().as_micros() / 4) as u32;
+        m.wrapping_add(self.key.hash_one((local, remote)) as u32)
+    }
+
+    /// the ISS of a SYN cookie: H1(tuple) + peer ISN + (count << 24) + (H2(tuple, count) + MSS
+    /// index) mod 2^24, where count is an 8 bits time counter.
+    pub fn cookie(
+        &self,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        peer_isn: u32,
+        mss: u16,
+    ) -> u32 {
+        self.encode_cookie(local, remote, peer_isn, mss, self.cookie_count())
+    }
+
+    /// checks the cookie acknowledged by the final ACK of a handshake, returns the MSS in it.
+    pub fn check_cookie(
+        &self,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        peer_isn: u32,
+        cookie: u32,
+    ) -> Option<u16> {
+        self.decode_cookie(local, remote, peer_isn, cookie, self.cookie_count())
+    }
+
+    fn encode_cookie(
+        &self,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        peer_isn: u32,
+        mss: u16,
+        count: u32,
+    ) -> u32 {
+        let index = COOKIE_MSS.iter().rposition(|&m| m <= mss).unwrap_or(0) as u32;
+        (self.key.hash_one((1u8, local, remote)) as u32)
+            .wrapping_add(peer_isn)
+            .wrapping_add(count << 24)
+            .wrapping_add(self.cookie_hash(local, remote, count).wrapping_add(index) & 0xFFFFFF)
+    }
+
+    /// the MSS in a cookie, if it was made at most COOKIE_MAX_AGE ticks before `now`.
+    fn decode_cookie(
+        &self,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        peer_isn: u32,
+        cookie: u32,
+        now: u32,
+    ) -> Option<u16> {
+        let v = cookie
+            .wrapping_sub(self.key.hash_one((1u8, local, remote)) as u32)
+            .wrapping_sub(peer_isn);
+        let count = v >> 24;
+        if now.wrapping_sub(count) & 0xFF > COOKIE_MAX_AGE {
+            return None;
+        }
+        let index = v.wrapping_sub(self.cookie_hash(local, remote, count)) & 0xFFFFFF;
+        COOKIE_MSS.get(index as usize).copied()
+    }
+
+    fn cookie_count(&self) -> u32 {
+        (self.epoch.elapsed().as_secs() / COOKIE_PERIOD.as_secs()) as u32 & 0xFF
+    }
+
+    fn cookie_hash(&self, local: (Ipv4Addr, u16), remote: (Ipv4Addr, u16), count: u32) -> u32 {
+        self.key.hash_one((2u8, local, remote, count)) as u32
+    }
+}
+
+/// the default number of challenge ACKs an interface sends per second, RFC 5961 7
+pub const CHALLENGE_ACK_LIMIT: u32 = 1000;
+
+/// The rate limit of the challenge ACKs of RFC 5961, shared by all the connections of an
+/// interface.
+pub struct ChallengeAcks {
+    limit: u32,
+    // the challenge ACKs which may still be sent in the current second
+    quota: u32,
+    second: Option<time::Instant>,
+    key: RandomState,
+}
+
+impl Default for ChallengeAcks {
+    fn default() -> Self {
+        Self {
+            limit: CHALLENGE_ACK_LIMIT,
+            quota: 0,
+            second: None,
+            key: RandomState::new(),
+        }
+    }
+}
+
+impl ChallengeAcks {
+    /// sets the number of challenge ACKs per second, 0 turns them off.
+    pub fn set_limit(&mut self, limit: u32) {
+        self.limit = limit;
+        self.quota = std::cmp::min(self.quota, limit);
+    }
+
+    /// takes one challenge ACK from the quota of the current second. The quota is the limit plus
+    /// a random part of up to half of it, so an attacker cannot count how many of its guesses
+    /// were answered, CVE-2016-5696.
+    fn take(&mut self) -> bool {
+        if self.limit == 0 {
+            return false;
+        }
+        let now = time::Instant::now();
+        if self
+            .second
+            .is_none_or(|second| now.duration_since(second) >= time::Duration::from_secs(1))
+        {
+            let jitter = self.key.hash_one(now) as u32 % (self.limit / 2 + 1);
+            self.quota = self.limit.saturating_add(jitter);
+            self.second = Some(now);
+        }
+        if self.quota == 0 {
+            return false;
+        }
+        self.quota -= 1;
+        true
+    }
+}
+
+/// A token bucket which paces the segments at the rate given by the congestion controller.
+struct Pacer {
+    // the number of bytes which may be sent now, negative if more has been sent
+    tokens: f64,
+    last: time::Instant,
+}
+
+impl Default for Pacer {
+    fn default() -> Self {
+        Self {
+            tokens: 0.0,
+            last: time::Instant::now(),
+        }
+    }
+}
+
+impl Pacer {
+    /// adds the tokens earned since the last refill. The bucket holds at most two ticks' worth
+    /// of tokens, so that the segments are not sent in bursts after an idle period.
+    fn refill(&mut self, rate: f64, mss: usize) {
+        let now = time::Instant::now();
+        let burst = rate * (2 * CLOCK_GRANULARITY).as_secs_f64() + (2 * mss) as f64;
+        self.tokens += rate * now.duration_since(self.last).as_secs_f64();
+        self.tokens = self.tokens.min(burst);
+        self.last = now;
+    }
+
+    fn available(&self) -> usize {
+        self.tokens.max(0.0) as usize
+    }
+}
+
+/// the default delay of an ACK, the same as the minimum of Linux
+pub const DEFAULT_ACK_DELAY: time::Duration = time::Duration::from_millis(40);
+/// the range of the ACK delay, which must stay well below 500ms, RFC 9293 3.8.6.3
+const ACK_DELAY_RANGE: (time::Duration, time::Duration) = (
+    time::Duration::from_millis(40),
+    time::Duration::from_millis(200),
+);
+
+/// Delayed ACKs, RFC 1122 4.2.3.2 and RFC 9293 3.8.6.3: an ACK is held back for a while, so it
+/// can be sent along with the data of the application, but at least every second full-sized
+/// segment is acknowledged at once.
+struct DelayedAck {
+    // when the held ACK must be sent, None if there is none
+    due: Option<time::Instant>,
+    // the bytes received since the last ACK
+    bytes: usize,
+    // the largest segment received, which is taken as the full size
+    rcv_mss: usize,
+    delay: time::Duration,
+    // every segment is acknowledged at once
+    quickack: bool,
+}
+
+impl Default for DelayedAck {
+    fn default() -> Self {
+        Self {
+            due: None,
+            bytes: 0,
+            rcv_mss: 0,
+            delay: DEFAULT_ACK_DELAY,
+            quickack: false,
+        }
+    }
 }
+
+impl DelayedAck {
+    /// records `len` bytes of data which arrived in a segment, returns whether the ACK has to be
+    /// sent now.
+    fn on_data(&mut self, len: usize, immediate: bool) -> bool {
+        self.rcv_mss = std::cmp::max(self.rcv_mss, len);
+        self.bytes += len;
+        if immediate || self.quickack || self.bytes >= 2 * self.rcv_mss {
+            return true;
+        }
+        self.due
+            .get_or_insert_with(|| time::Instant::now() + self.delay);
+        false
+    }
+
+    /// every segment we send acknowledges all the data received so far.
+    fn on_sent(&mut self) {
+        self.due = None;
+        self.bytes = 0;
+    }
+
+    fn expired(&self) -> bool {
+        self.due.is_some_and(|due| time::Instant::now() >= due)
+    }
+}
+
+/// The keepalive of RFC 1122 4.2.3.6, which finds the peers which have gone away from idle
+/// connections. The defaults are the ones of RFC 1122 and Linux.
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    /// how long nothing has been received before the first probe
+    pub idle: time::Duration,
+    /// the interval between two probes
+    pub interval: time::Duration,
+    /// the number of unanswered probes after which the connection is aborted
+    pub probes: u32,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            idle: time::Duration::from_secs(2 * 60 * 60),
+            interval: time::Duration::from_secs(75),
+            probes: 9,
+        }
+    }
+}
+
+/// A snapshot of the state of a connection.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    /// smoothed round trip time, None before the first measurement
+    pub srtt: Option<time::Duration>,
+    /// round trip time variation
+    pub rttvar: time::Duration,
+    /// the current retransmission timeout, which includes the backoff
+    pub rto: time::Duration,
+    /// the name of the congestion control algorithm
+    pub congestion: &'static str,
+    /// congestion window in bytes
+    pub cwnd: usize,
+    /// slow start threshold in bytes
+    pub ssthresh: usize,
+    /// the state of the congestion control algorithm
+    pub congestion_state: String,
+    /// the retransmissions of a segment have passed R1 without an ACK
+    pub trouble: bool,
+}
+
+/// A segment on the retransmission queue.
+struct Segment {
+    /// the first sequence number of the segment
+    seqn: u32,
+    /// the length in sequence space, which includes SYN and FIN
+    len: u32,
+    syn: bool,
+    fin: bool,
+    /// when the segment was sent for the last time
+    sent: time::Instant,
+    /// how many times the segment has been retransmitted
+    retransmits: u32,
+    /// the delivery state when the segment was sent for the last time
+    tx: congestion::TxState,
+}
+impl Segment {
+    /// the number of data bytes in the segment
+    fn data_len(&self) -> usize {
+        (self.len - self.syn as u32 - self.fin as u32) as usize
+    }
+}
+#[allow(dead_code)]
 #[derive(Debug, PartialEq, Eq)]
 enum State {
     Listen,
@@ -55,18 +595,24 @@ struct SendSequenceSpace {
     /// next sequence number for sending
     nxt: u32,
     /// send window
-    wnd: u16,
+    wnd: u32,
+    /// the largest window the peer has advertised, MAX.SND.WND of RFC 5961
+    max_wnd: u32,
+    /// the window scale of the peer, RFC 7323
+    shift: u8,
 
     wl1: u32,
     wl2: u32,
 }
 impl SendSequenceSpace {
-    fn new(irs: u32, wnd: u16) -> Self {
+    fn new(iss: u32, irs: u32, wnd: u32) -> Self {
         Self {
-            una: 0,
-            nxt: 0,
-            iss: 0,
+            una: iss,
+            nxt: iss,
+            iss,
             wnd,
+            max_wnd: wnd,
+            shift: 0,
             wl1: irs,
             wl2: irs,
         }
@@ -79,20 +625,110 @@ struct RecvSequenceSpace {
     /// receive next, which equals to received sequence number + 1
     nxt: u32,
     /// receive window
-    wnd: u16,
+    wnd: u32,
+    /// our window scale, RFC 7323
+    shift: u8,
     /// initial received sequence number
     irs: u32,
 }
 impl RecvSequenceSpace {
-    fn new(irs: u32) -> Self {
+    fn new(irs: u32, wnd: u32) -> Self {
         Self {
-            nxt: irs + 1,
-            wnd: 1024,
+            nxt: irs.wrapping_add(1),
+            wnd,
+            shift: window_shift(MAX_RECV_BUFFER as u32),
             irs,
         }
     }
 }
 
+/// the shift which makes `wnd` fit into the 16 bits window field, at most 14, RFC 7323 2.3
+fn window_shift(wnd: u32) -> u8 {
+    let mut shift = 0;
+    while shift < MAX_WINDOW_SHIFT && wnd >> shift > u16::MAX as u32 {
+        shift += 1;
+    }
+    shift
+}
+
+/// Reassembly queue for the segments which arrived out of order. See RFC 793 page 69:
+/// segments with higher beginning sequence numbers may be held for later processing.
+#[derive(Default)]
+struct Assembler {
+    /// sorted, non-overlapping and non-adjacent ranges of data, all of them start after RCV.NXT
+    segments: VecDeque<(u32, Vec<u8>)>,
+    /// the sequence number of a FIN which arrived before the data in front of it
+    fin: Option<u32>,
+}
+impl Assembler {
+    /// stores the part of data which lies in the receive window [nxt, nxt + wnd). Overlapping
+    /// parts are merged, the bytes already queued are kept.
+    fn insert(&mut self, nxt: u32, wnd: u32, seqn: u32, data: &[u8]) {
+        // offsets relative to RCV.NXT, negative for data which has been received.
+        let offset = |seq: u32| seq.wrapping_sub(nxt) as i32 as i64;
+        let seg_start = offset(seqn);
+        let mut start = std::cmp::max(seg_start, 0);
+        let mut end = std::cmp::min(seg_start + data.len() as i64, wnd as i64);
+        if start >= end {
+            return;
+        }
+        let mut merged = data[(start - seg_start) as usize..(end - seg_start) as usize].to_vec();
+
+        let mut at = self.segments.len();
+        let mut i = 0;
+        while i < self.segments.len() {
+            let s = offset(self.segments[i].0);
+            let e = s + self.segments[i].1.len() as i64;
+            if e < start {
+                i += 1;
+                continue;
+            }
+            if end < s {
+                at = i;
+                break;
+            }
+            // overlapping or adjacent, merge it with the new data
+            let (_, old) = self.segments.remove(i).unwrap();
+            let new_start = std::cmp::min(s, start);
+            let new_end = std::cmp::max(e, end);
+            let mut buf = vec![0u8; (new_end - new_start) as usize];
+            buf[(start - new_start) as usize..(end - new_start) as usize].copy_from_slice(&merged);
+            buf[(s - new_start) as usize..(e - new_start) as usize].copy_from_slice(&old);
+            merged = buf;
+            start = new_start;
+            end = new_end;
+            at = i;
+        }
+        self.segments
+            .insert(at, (nxt.wrapping_add(start as u32), merged));
+    }
+
+    /// the ranges of the queued data, [start, end)
+    fn ranges(&self) -> Vec<(u32, u32)> {
+        self.segments
+            .iter()
+            .map(|(seqn, data)| (*seqn, seqn.wrapping_add(data.len() as u32)))
+            .collect()
+    }
+
+    /// the first part of [start, end) which is queued already.
+    fn overlap(&self, start: u32, end: u32) -> Option<(u32, u32)> {
+        self.ranges().into_iter().find_map(|(s, e)| {
+            let s = if util::lt(s, start) { start } else { s };
+            let e = if util::lt(end, e) { end } else { e };
+            util::lt(s, e).then_some((s, e))
+        })
+    }
+
+    /// takes the data which starts at RCV.NXT, if the gap in front of it has been filled.
+    fn pop(&mut self, nxt: u32) -> Option<Vec<u8>> {
+        match self.segments.front() {
+            Some((seqn, _)) if *seqn == nxt => self.segments.pop_front().map(|(_, data)| data),
+            _ => None,
+        }
+    }
+}
+
 /// actions when segment arrives
 #[derive(Debug)]
 pub enum Action {
@@ -107,6 +743,8 @@ pub enum Action {
     // to receive payloads, we have to call stream to read no matter whether the payload is empty
     // or not.
     Read,
+    // the handshake is completed, anyone who is waiting for the connection should be waked up.
+    Estab,
 }
 
 #[derive(Debug)]
@@ -120,116 +758,508 @@ pub enum Request {
 }
 
 impl TCB {
-    fn new(ip_header: etherparse::Ipv4Header, tcp_header: etherparse::TcpHeader) -> Self {
+    fn new(
+        ip_header: etherparse::Ipv4Header,
+        tcp_header: etherparse::TcpHeader,
+        iss: u32,
+        recv_buffer: usize,
+    ) -> Self {
+        let mut tcb = Self::init(
+            State::SynRcvd,
+            (ip_header.destination.into(), tcp_header.destination_port),
+            (ip_header.source.into(), tcp_header.source_port),
+            iss,
+            recv_buffer,
+        );
+        tcb.send = SendSequenceSpace::new(
+            iss,
+            tcp_header.sequence_number,
+            tcp_header.window_size as u32,
+        );
+        tcb.recv = RecvSequenceSpace::new(tcp_header.sequence_number, tcb.recv_buffer as u32);
+        let options = options::Options::parse(tcp_header.options_iterator());
+        tcb.sack_permitted = options.sack_permitted;
+        tcb.negotiate_window_scale(options.window_scale);
+        tcb.timestamps.on_syn(options.timestamp);
+        tcb.negotiate_mss(options.mss);
+        tcb
+    }
+
+    /// answers a SYN with a SYN cookie as the ISS, no state is kept for the connection. There is
+    /// nowhere to remember the options of the SYN, so only the MSS is used.
+    pub fn send_syn_cookie(
+        nic: &mut Iface,
+        ip_header: etherparse::Ipv4HeaderSlice,
+        tcp_header: etherparse::TcpHeaderSlice,
+        cookie: u32,
+        recv_buffer: usize,
+    ) -> io::Result<()> {
+        let mut tcb = TCB::new(
+            ip_header.to_header(),
+            tcp_header.to_header(),
+            cookie,
+            recv_buffer,
+        );
+        tcb.disable_options();
+        tcb.tcp_header.syn = true;
+        tcb.tcp_header.ack = true;
+        tcb.write(nic, Request::SYNACK)?;
+        Ok(())
+    }
+
+    /// resets a segment for which no connection is kept, RFC 793 page 36. The RST takes its
+    /// sequence number from the ACK field, or acknowledges the segment if it has no ACK.
+    pub fn send_reset(
+        nic: &mut Iface,
+        ip_header: &etherparse::Ipv4HeaderSlice,
+        tcp_header: &etherparse::TcpHeaderSlice,
+        data_len: usize,
+    ) -> io::Result<()> {
+        if tcp_header.rst() {
+            return Ok(());
+        }
+        let mut tcb = Self::init(
+            State::Closed,
+            (ip_header.destination_addr(), tcp_header.destination_port()),
+            (ip_header.source_addr(), tcp_header.source_port()),
+            0,
+            0,
+        );
+        tcb.disable_options();
+        if tcp_header.ack() {
+            tcb.send.nxt = tcp_header.acknowledgment_number();
+        } else {
+            let seg_len = data_len as u32 + tcp_header.syn() as u32 + tcp_header.fin() as u32;
+            tcb.recv.nxt = tcp_header.sequence_number().wrapping_add(seg_len);
+            tcb.tcp_header.ack = true;
+        }
+        tcb.write(nic, Request::RST)?;
+        Ok(())
+    }
+
+    /// rebuilds an established connection from the final ACK of a handshake which was answered
+    /// with a SYN cookie, `mss` is the one in the cookie.
+    pub fn from_cookie(
+        ip_header: &etherparse::Ipv4HeaderSlice,
+        tcp_header: &etherparse::TcpHeaderSlice,
+        mss: u16,
+        recv_buffer: usize,
+    ) -> Self {
+        let iss = tcp_header.acknowledgment_number().wrapping_sub(1);
+        let irs = tcp_header.sequence_number().wrapping_sub(1);
+        let mut tcb = Self::init(
+            State::Estab,
+            (ip_header.destination_addr(), tcp_header.destination_port()),
+            (ip_header.source_addr(), tcp_header.source_port()),
+            iss,
+            recv_buffer,
+        );
+        tcb.send = SendSequenceSpace::new(iss, irs, tcp_header.window_size() as u32);
+        tcb.send.una = iss.wrapping_add(1);
+        tcb.send.nxt = tcb.send.una;
+        tcb.recv = RecvSequenceSpace::new(irs, tcb.recv_buffer as u32);
+        tcb.disable_options();
+        tcb.negotiate_mss(Some(mss));
+        tcb.tcp_header.ack = true;
+        tcb
+    }
+
+    /// turns off the options which are negotiated in the handshake.
+    fn disable_options(&mut self) {
+        self.sack_permitted = false;
+        self.negotiate_window_scale(None);
+        self.timestamps.on_syn(None);
+    }
+
+    /// takes the MSS option of the SYN of the peer, 536 is assumed without it, RFC 9293 3.7.1.
+    /// The congestion controller starts over with the new MSS.
+    fn negotiate_mss(&mut self, peer: Option<u16>) {
+        let peer = peer.map_or(congestion::DEFAULT_MSS, |mss| mss as usize);
+        self.mss = peer.clamp(MIN_MSS, LOCAL_MSS);
+        self.cc = self.algorithm.build(self.mss);
+    }
+
+    /// applies the window scale option of the SYN of the peer. Both sides scale only if both
+    /// sides send the option, RFC 7323 2.2.
+    fn negotiate_window_scale(&mut self, peer: Option<u8>) {
+        match peer {
+            Some(shift) if self.window_scaling => {
+                self.send.shift = std::cmp::min(shift, MAX_WINDOW_SHIFT);
+            }
+            _ => {
+                self.window_scaling = false;
+                self.send.shift = 0;
+                self.recv.shift = 0;
+            }
+        }
+    }
+
+    fn init(
+        state: State,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        iss: u32,
+        recv_buffer: usize,
+    ) -> Self {
+        let sack_permitted = state == State::SynSent;
+        let recv_buffer = std::cmp::min(recv_buffer, MAX_RECV_BUFFER);
         Self {
-            state: State::SynRcvd,
-            send: SendSequenceSpace::new(tcp_header.sequence_number, tcp_header.window_size),
-            recv: RecvSequenceSpace::new(tcp_header.sequence_number),
+            state,
+            send: SendSequenceSpace::new(iss, 0, 0),
+            recv: RecvSequenceSpace::new(0, recv_buffer as u32),
             ip_header: etherparse::Ipv4Header::new(
                 0,
                 64,
                 etherparse::IpTrafficClass::Tcp,
-                ip_header.destination,
-                ip_header.source,
-            ),
-            tcp_header: etherparse::TcpHeader::new(
-                tcp_header.destination_port,
-                tcp_header.source_port,
-                0,
-                1024,
+                local.0.octets(),
+                remote.0.octets(),
             ),
+            tcp_header: etherparse::TcpHeader::new(local.1, remote.1, 0, 1024),
             incoming: VecDeque::default(),
+            assembler: Assembler::default(),
             outgoing: Default::default(),
             closed: false,
             closed_at: None,
-            timers: Timers {
-                send_times: Default::default(),
-                srtt: time::Duration::from_secs(1 * 60).as_secs_f64(),
-            },
+            error: None,
+            timers: Timers::default(),
+            retransmission: VecDeque::new(),
+            cc: congestion::Algorithm::default().build(congestion::DEFAULT_MSS),
+            algorithm: congestion::Algorithm::default(),
+            mss: congestion::DEFAULT_MSS,
+            delivery: congestion::Delivery::default(),
+            pacer: Pacer::default(),
+            dup_acks: 0,
+            limited_transmit: 0,
+            rto_recover: None,
+            sack_permitted,
+            window_scaling: true,
+            msl: DEFAULT_MSL,
+            window_probe: false,
+            delayed_ack: DelayedAck::default(),
+            nodelay: false,
+            keepalive: None,
+            last_recv: time::Instant::now(),
+            keepalive_probes: 0,
+            window_probes: 0,
+            r1: DEFAULT_R1,
+            r2: DEFAULT_R2,
+            user_timeout: None,
+            last_progress: time::Instant::now(),
+            trouble: false,
+            snd_up: None,
+            send_buffer: DEFAULT_SEND_BUFFER,
+            recv_buffer,
+            window_update: false,
+            autotune: autotune::Autotune::new(recv_buffer),
+            urgent: urgent::Receiver::default(),
+            timestamps: options::Timestamps::default(),
+            sack: sack::Receiver::default(),
+            scoreboard: sack::Scoreboard::default(),
         }
     }
+
+    /// Active OPEN, RFC 793 page 54.
+    /// The TCB starts in SynSent, the SYN itself is sent by on_tick. The SYN offers a window of
+    /// `recv_buffer` bytes, as does the SYN-ACK of a passive open.
+    pub fn connect(
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        iss: u32,
+        recv_buffer: usize,
+    ) -> Self {
+        Self::init(State::SynSent, local, remote, iss, recv_buffer)
+    }
+    /// Passive OPEN: a SYN arrived at a listening port. The SYN-ACK is sent by send_synack,
+    /// once the connection is configured.
     pub fn new_connection(
-        nic: &mut Iface,
         ip_header: etherparse::Ipv4HeaderSlice,
         tcp_header: etherparse::TcpHeaderSlice,
-    ) -> io::Result<Option<Self>> {
+        iss: u32,
+        recv_buffer: usize,
+    ) -> Option<Self> {
         if !tcp_header.syn() {
-            return Ok(None);
+            return None;
         }
-        let mut tcb = TCB::new(ip_header.to_header(), tcp_header.to_header());
-
-        tcb.tcp_header.syn = true;
-        tcb.tcp_header.ack = true;
-
-        // tcb.write(nic, tcb.send.nxt, 0).unwrap();
-        tcb.write(nic, Request::SYNACK).unwrap();
+        Some(TCB::new(
+            ip_header.to_header(),
+            tcp_header.to_header(),
+            iss,
+            recv_buffer,
+        ))
+    }
 
-        Ok(Some(tcb))
+    /// answers the SYN of a passive open.
+    pub fn send_synack(&mut self, nic: &mut Iface) -> io::Result<()> {
+        self.tcp_header.syn = true;
+        self.tcp_header.ack = true;
+        self.write(nic, Request::SYNACK)?;
+        Ok(())
     }
 
     /// This function does three things:
-    /// calculates the proper length of sending buffer from the unacked queue and adjusts TCB
-    /// sends the buffer with tcp header to the nic
+    /// decides the sequence number and the part of outgoing to be sent for the request
+    /// sends the segment to the nic, and puts it into the retransmission queue if it occupies
+    /// sequence space
     /// return the length of sent buffer.
     fn write(&mut self, nic: &mut Iface, req: Request) -> io::Result<usize> {
-        let mut buf = [0u8; 1500];
-        let mut next_seqn = 0u32;
+        self.pace();
+        let unsent = self.unsent();
 
-        self.tcp_header.sequence_number = match req {
+        let len = match req {
             Request::SYN | Request::SYNACK => {
                 self.tcp_header.syn = true;
-                next_seqn += 1;
-                self.send.nxt
+                0
             }
             Request::RST => {
                 self.tcp_header.rst = true;
-                self.send.nxt
+                0
             }
             Request::FIN => {
                 // FIXME: Do we have to respect the zero receive window?
+                // The FIN is only sent along with the last byte of outgoing, when all the unsent
+                // data fits into the window.
                 assert!((self.state == State::FinWait1) | (self.state == State::LastAck));
-                self.tcp_header.fin = true;
-                next_seqn += 1;
-                self.send.nxt
+                let len = std::cmp::min(unsent, self.segment_room());
+                self.tcp_header.fin = len == unsent;
+                len
             }
             Request::ReTransmit => {
                 //The sending TCP must regularly retransmit to the receiving TCP even when the window
                 //is zero.
-                if let State::FinWait1 | State::LastAck = self.state {
-                    self.tcp_header.fin = true;
-                };
-                self.send.una
+                let una = self.send.una;
+                return Ok(self.retransmit(nic, una)?.map_or(0, |(_, len)| len));
             }
-            Request::ACK => self.send.nxt,
+            Request::ACK if self.nagle_allows() => std::cmp::min(unsent, self.segment_room()),
+            // the small data held back by Nagle's algorithm does not ride on an ACK either
+            Request::ACK => 0,
         };
-        self.tcp_header.acknowledgment_number = self.recv.nxt;
 
-        // length of the unacked data
-        let offset = self.send.nxt.wrapping_sub(self.send.una) as usize;
+        let seqn = self.send.nxt;
+        let (syn, fin, rst) = (
+            self.tcp_header.syn,
+            self.tcp_header.fin,
+            self.tcp_header.rst,
+        );
+        self.send_segment(nic, seqn, len)?;
+        if rst {
+            return Ok(0);
+        }
+
+        let seg_len = len as u32 + syn as u32 + fin as u32;
+        if seg_len > 0 {
+            if self.flight_size() == 0 {
+                self.last_progress = time::Instant::now();
+            }
+            if fin {
+                self.closed_at = Some(seqn.wrapping_add(len as u32));
+            }
+            let now = time::Instant::now();
+            self.retransmission.push_back(Segment {
+                seqn,
+                len: seg_len,
+                syn,
+                fin,
+                sent: now,
+                retransmits: 0,
+                tx: self.delivery.on_send(self.flight_size(), now),
+            });
+            self.pacer.tokens -= len as f64;
+            self.send.nxt = self.send.nxt.wrapping_add(seg_len);
+            self.timers.start();
+        }
+        Ok(len)
+    }
+
+    /// retransmits the first range at or after `from` which the peer does not hold, within the
+    /// segment on the retransmission queue it belongs to. Returns the end of the range and the
+    /// number of data bytes sent, None if there is nothing to retransmit.
+    fn retransmit(&mut self, nic: &mut Iface, from: u32) -> io::Result<Option<(u32, usize)>> {
+        let mut start = from;
+        while let Some(end) = self.scoreboard.held(start) {
+            start = end;
+        }
+        let flight = self.flight_size();
+        let seg = match self
+            .retransmission
+            .iter_mut()
+            .find(|seg| util::lt(start, seg.seqn.wrapping_add(seg.len)))
+        {
+            Some(seg) => seg,
+            None => return Ok(None),
+        };
+        if util::lt(start, seg.seqn) {
+            start = seg.seqn;
+        }
+        let seg_end = seg.seqn.wrapping_add(seg.len);
+        let end = match self.scoreboard.next_held(start) {
+            Some(held) if util::lt(held, seg_end) => held,
+            _ => seg_end,
+        };
+        seg.sent = time::Instant::now();
+        seg.retransmits += 1;
+        seg.tx = self.delivery.on_send(flight, seg.sent);
+        let syn = seg.syn && start == seg.seqn;
+        let fin = seg.fin && end == seg_end;
+        let len = (end.wrapping_sub(start) - syn as u32 - fin as u32) as usize;
+        self.pacer.tokens -= len as f64;
+        self.tcp_header.syn = syn;
+        self.tcp_header.fin = fin;
+        debug!("tcp::write: retransmit {} bytes from {:?}", len, start);
+        self.send_segment(nic, start, len)?;
+        Ok(Some((end, len)))
+    }
+
+    /// retransmits the next hole below the highest range the peer holds in fast recovery, which
+    /// has not been retransmitted in this recovery, RFC 6675 style.
+    fn retransmit_hole(&mut self, nic: &mut Iface) -> io::Result<bool> {
+        let highest = match self.scoreboard.highest() {
+            Some(highest) => highest,
+            None => return Ok(false),
+        };
+        let mut start = match self.scoreboard.high_rxt {
+            Some(rxt) if util::lt(self.send.una, rxt) => rxt,
+            _ => self.send.una,
+        };
+        while let Some(end) = self.scoreboard.held(start) {
+            start = end;
+        }
+        if !util::lt(start, highest) {
+            return Ok(false);
+        }
+        if let Some((end, _)) = self.retransmit(nic, start)? {
+            self.scoreboard.high_rxt = Some(end);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// after a timeout, retransmits the ranges below the recovery point which the peer does not
+    /// hold and which have not been retransmitted since, as long as cwnd allows. The data
+    /// retransmitted before the timeout is taken as lost.
+    fn retransmit_lost(&mut self, nic: &mut Iface) -> io::Result<()> {
+        let una = self.send.una;
+        let recover = match self.rto_recover {
+            Some(recover) if util::lt(una, recover) => recover,
+            _ => {
+                self.rto_recover = None;
+                return Ok(());
+            }
+        };
+        loop {
+            let mut start = match self.scoreboard.high_rxt {
+                Some(rxt) if util::lt(una, rxt) => rxt,
+                _ => una,
+            };
+            if self.scoreboard.missing(una, start) >= self.cc.cwnd() {
+                return Ok(());
+            }
+            while let Some(end) = self.scoreboard.held(start) {
+                start = end;
+            }
+            if !util::lt(start, recover) {
+                return Ok(());
+            }
+            match self.retransmit(nic, start)? {
+                Some((end, _)) => self.scoreboard.high_rxt = Some(end),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// the options of the next segment, which carries `len` bytes of data. Timestamps go into
+    /// every segment but RST once they are negotiated. A SYN advertises the MSS and offers window
+    /// scaling and SACK, other segments carry the SACK blocks of the out-of-order data.
+    fn options(&mut self, len: usize) -> Vec<TcpOptionElement> {
+        let mut options = vec![];
+        if self.timestamps.enabled && !self.tcp_header.rst {
+            options.extend([
+                TcpOptionElement::Nop,
+                TcpOptionElement::Nop,
+                self.timestamps.option(self.recv.nxt),
+            ]);
+        }
+        if self.tcp_header.syn {
+            options.push(TcpOptionElement::MaximumSegmentSize(LOCAL_MSS as u16));
+            if self.window_scaling {
+                options.extend([
+                    TcpOptionElement::Nop,
+                    TcpOptionElement::WindowScale(self.recv.shift),
+                ]);
+            }
+            if self.sack_permitted {
+                options.extend([
+                    TcpOptionElement::Nop,
+                    TcpOptionElement::Nop,
+                    TcpOptionElement::SelectiveAcknowledgementPermitted,
+                ]);
+            }
+            return options;
+        }
+        if !self.sack_permitted {
+            return options;
+        }
+        // the blocks must fit into both the option space and the MSS, each block takes 8 bytes
+        // after the 2 NOPs, the kind and the length.
+        let room = std::cmp::min(40, self.mss.saturating_sub(len))
+            .saturating_sub(options::len(&options))
+            .saturating_sub(4)
+            / 8;
+        let blocks = self
+            .sack
+            .blocks(&self.assembler.ranges(), std::cmp::min(room, 4));
+        if let Some((&first, rest)) = blocks.split_first() {
+            let mut others = [None; 3];
+            for (other, &block) in others.iter_mut().zip(rest) {
+                *other = Some(block);
+            }
+            options.extend([
+                TcpOptionElement::Nop,
+                TcpOptionElement::Nop,
+                TcpOptionElement::SelectiveAcknowledgement(first, others),
+            ]);
+        }
+        options
+    }
+
+    /// builds a segment which carries `len` bytes of outgoing from sequence number `seqn`,
+    /// the control bits are taken from the tcp header and cleared after sending.
+    fn send_segment(&mut self, nic: &mut Iface, seqn: u32, len: usize) -> io::Result<()> {
+        let mut buf = [0u8; MTU];
+        let options = self.options(len);
+        self.tcp_header.set_options(&options).unwrap();
+        self.open_window();
+        self.window_update = false;
+        self.tcp_header.window_size = self.advertised_window();
+        self.tcp_header.sequence_number = seqn;
+        self.tcp_header.acknowledgment_number = self.recv.nxt;
+        // every segment in front of the urgent data points to its end, RFC 9293 3.8.5
+        match self.snd_up {
+            Some(up) if util::lt(seqn, up) => {
+                self.tcp_header.urg = true;
+                self.tcp_header.urgent_pointer =
+                    std::cmp::min(up.wrapping_sub(seqn), u16::MAX as u32) as u16;
+            }
+            _ => {
+                self.tcp_header.urg = false;
+                self.tcp_header.urgent_pointer = 0;
+            }
+        }
         debug!(
             "send.una: {:?}, snd.nxt: {:?}",
             self.send.una, self.send.nxt
         );
 
-        let payload: &[u8] = {
-            if let Request::SYN | Request::SYNACK | Request::RST = req {
-                &[]
-            } else if self.outgoing.is_empty() {
-                &[]
-            } else {
-                if let Request::ReTransmit = req {
-                    &self.outgoing.as_slices().0[..offset]
-                } else {
-                    &self.outgoing.as_slices().0[offset..]
-                }
-            }
+        let payload: &[u8] = if len == 0 {
+            &[]
+        } else {
+            let offset = self.data_offset(seqn);
+            &self.outgoing.make_contiguous()[offset..offset + len]
         };
         debug!("tcp::write: payload: {} bytes", payload.len());
 
-        let data_size = std::cmp::min(
-            buf.len(),
-            self.tcp_header.header_len() as usize + self.ip_header.header_len() + payload.len(),
-        );
+        let data_size =
+            self.tcp_header.header_len() as usize + self.ip_header.header_len() + payload.len();
+        assert!(data_size <= buf.len(), "segment exceeds the MTU");
         self.ip_header
             .set_payload_len(data_size - self.ip_header.header_len())
             .unwrap();
@@ -244,31 +1274,173 @@ impl TCB {
         self.ip_header.write(&mut unwritten).unwrap();
         let ip_header_ends_at = buf_len - unwritten.len();
 
-        unwritten = &mut unwritten[self.tcp_header.header_len() as usize..];
-        let tcp_header_ends_at = buf_len - unwritten.len();
+        unwritten = &mut unwritten[self.tcp_header.header_len() as usize..];
+        let tcp_header_ends_at = buf_len - unwritten.len();
+
+        unwritten.write_all(payload).unwrap();
+        let payload_ends_at = buf_len - unwritten.len();
+
+        self.tcp_header.checksum = self
+            .tcp_header
+            .calc_checksum_ipv4(&self.ip_header, &buf[tcp_header_ends_at..payload_ends_at])
+            .unwrap();
+
+        let mut tcp_header_buf = &mut buf[ip_header_ends_at..tcp_header_ends_at];
+        self.tcp_header.write(&mut tcp_header_buf).unwrap();
+
+        nic.send(&buf[..payload_ends_at]).unwrap();
+
+        self.tcp_header.fin = false;
+        self.tcp_header.syn = false;
+        self.tcp_header.rst = false;
+        self.delayed_ack.on_sent();
+        Ok(())
+    }
+
+    /// the index in outgoing of the data at sequence number `seqn`.
+    /// outgoing starts at SND.UNA, except that the SYN occupies SND.UNA during the handshake.
+    fn data_offset(&self, seqn: u32) -> usize {
+        let offset = seqn.wrapping_sub(self.send.una) as usize;
+        if let State::SynSent | State::SynRcvd = self.state {
+            offset.saturating_sub(1)
+        } else {
+            offset
+        }
+    }
+
+    /// the number of bytes which are sent but not acknowledged, in sequence space.
+    fn flight_size(&self) -> usize {
+        self.send.nxt.wrapping_sub(self.send.una) as usize
+    }
+
+    /// how many more bytes may be sent, which is limited by both the window of the peer and the
+    /// congestion window. Nothing may be sent while the pacer holds the next segment back.
+    fn send_window(&self) -> usize {
+        if !self.pacer_allows() {
+            return 0;
+        }
+        let cwnd = self.cc.cwnd() + self.limited_transmit;
+        std::cmp::min(self.send.wnd as usize, cwnd).saturating_sub(self.flight_size())
+    }
+
+    /// whether the pacer has the tokens for the next segment if the congestion controller paces.
+    /// The segment waits for them rather than being cut down to a runt.
+    fn pacer_allows(&self) -> bool {
+        self.cc.pacing_rate().is_none()
+            || self.pacer.available() >= std::cmp::min(self.unsent(), self.full_segment())
+    }
+
+    /// how many bytes of data the next new segment may carry, which is limited by the windows
+    /// and the MSS. The MSS includes the options, the timestamps option is in every segment.
+    fn segment_room(&self) -> usize {
+        if self.window_probe {
+            return 1;
+        }
+        std::cmp::min(self.send_window(), self.full_segment())
+    }
+
+    /// the data in a full-sized segment, which is the MSS without the options.
+    fn full_segment(&self) -> usize {
+        let options = if self.timestamps.enabled { 12 } else { 0 };
+        self.mss.saturating_sub(options).max(1)
+    }
+
+    /// Nagle's algorithm, RFC 1122 4.2.3.4: while data is in flight, only full-sized segments
+    /// are sent, and the rest waits for the ACK.
+    fn nagle_allows(&self) -> bool {
+        self.nodelay
+            || self.snd_up.is_some()
+            || self.flight_size() == 0
+            || std::cmp::min(self.unsent(), self.segment_room()) >= self.full_segment()
+    }
+
+    /// refills the pacer at the current pacing rate.
+    fn pace(&mut self) {
+        match self.cc.pacing_rate() {
+            Some(rate) => self.pacer.refill(rate, self.mss),
+            None => self.pacer = Pacer::default(),
+        }
+    }
+
+    /// takes the window of an acceptable ACK, unless the segment is older than the one which last
+    /// updated it, RFC 793 page 72.
+    fn update_window(&mut self, tcp_header: &etherparse::TcpHeaderSlice) {
+        let seqn = tcp_header.sequence_number();
+        let ackn = tcp_header.acknowledgment_number();
+        if util::lt(self.send.wl1, seqn) || (self.send.wl1 == seqn && util::le(self.send.wl2, ackn))
+        {
+            self.send.wnd = self.scaled_window(tcp_header);
+            self.send.max_wnd = std::cmp::max(self.send.max_wnd, self.send.wnd);
+            self.send.wl1 = seqn;
+            self.send.wl2 = ackn;
+        }
+    }
 
-        unwritten.write(payload).unwrap();
-        let payload_ends_at = buf_len - unwritten.len();
+    /// the send window in a segment of the peer, RFC 7323 2.3
+    fn scaled_window(&self, tcp_header: &etherparse::TcpHeaderSlice) -> u32 {
+        (tcp_header.window_size() as u32) << self.send.shift
+    }
 
-        self.tcp_header.checksum = self
-            .tcp_header
-            .calc_checksum_ipv4(&self.ip_header, &buf[tcp_header_ends_at..payload_ends_at])
-            .unwrap();
+    /// opens the receive window to the free space of the receive buffer. Receiver side SWS
+    /// avoidance, RFC 9293 3.8.6.2.2: the right edge only moves on by at least min(buffer / 2,
+    /// MSS), and it never moves back. Returns whether the window has been opened.
+    fn open_window(&mut self) -> bool {
+        let largest = (u16::MAX as u32) << self.recv.shift;
+        let free = self.recv_buffer.saturating_sub(self.incoming.len()) as u32;
+        let free = std::cmp::min(free, largest);
+        let threshold = std::cmp::min(self.recv_buffer / 2, self.mss) as u32;
+        if free < self.recv.wnd.saturating_add(threshold) {
+            return false;
+        }
+        self.recv.wnd = free;
+        true
+    }
 
-        let mut tcp_header_buf = &mut buf[ip_header_ends_at..tcp_header_ends_at];
-        self.tcp_header.write(&mut tcp_header_buf).unwrap();
+    /// the receive window in the window field, which is never scaled in a SYN, RFC 7323 2.2
+    fn advertised_window(&self) -> u16 {
+        let wnd = if self.tcp_header.syn {
+            self.recv.wnd
+        } else {
+            self.recv.wnd >> self.recv.shift
+        };
+        std::cmp::min(wnd, u16::MAX as u32) as u16
+    }
 
-        nic.send(&buf[..payload_ends_at]).unwrap();
+    /// the number of bytes in outgoing which have never been sent.
+    fn unsent(&self) -> usize {
+        self.outgoing
+            .len()
+            .saturating_sub(self.data_offset(self.send.nxt))
+    }
 
-        next_seqn += payload.len() as u32;
-        self.send.nxt = self.send.nxt.wrapping_add(next_seqn);
-        self.tcp_header.fin = false;
-        self.tcp_header.syn = false;
-        self.timers
-            .send_times
-            .insert(self.send.una, time::Instant::now());
+    /// whether we have closed but the FIN has not been sent yet.
+    fn fin_pending(&self) -> bool {
+        matches!(self.state, State::FinWait1 | State::LastAck) && self.closed_at.is_none()
+    }
+
+    /// sends a window probe: the front of the retransmission queue if there is one, otherwise one
+    /// new byte, or the FIN if all the data has been sent.
+    fn probe(&mut self, nic: &mut Iface) -> io::Result<()> {
+        if self.flight_size() > 0 {
+            let una = self.send.una;
+            self.retransmit(nic, una)?;
+            return Ok(());
+        }
+        let req = if self.unsent() == 0 {
+            Request::FIN
+        } else {
+            Request::ACK
+        };
+        self.window_probe = true;
+        let sent = self.write(nic, req);
+        self.window_probe = false;
+        sent.map(|_| ())
+    }
 
-        Ok(payload.len())
+    /// whether our FIN has been acknowledged.
+    fn fin_acked(&self) -> bool {
+        self.closed_at
+            .is_some_and(|fin| util::lt(fin, self.send.una))
     }
 
     pub fn close(&mut self) -> io::Result<()> {
@@ -298,16 +1470,46 @@ impl TCB {
         };
         Ok(())
     }
+    /// whether the handshake of this connection is still in progress.
+    pub fn is_connecting(&self) -> bool {
+        matches!(self.state, State::SynSent | State::SynRcvd)
+    }
+
+    /// checks whether a SYN may take over this connection in TIME-WAIT, RFC 6191: its timestamp
+    /// must be newer than the last one of this incarnation, or without timestamps, its sequence
+    /// number must be above RCV.NXT. Returns the lowest ISS the new incarnation may use.
+    pub fn reopen(&self, tcp_header: &etherparse::TcpHeaderSlice) -> Option<u32> {
+        if self.state != State::TimeWait
+            || !tcp_header.syn()
+            || tcp_header.ack()
+            || tcp_header.rst()
+        {
+            return None;
+        }
+        let options = options::Options::parse(tcp_header.options_iterator());
+        let newer = self
+            .timestamps
+            .is_newer(options.timestamp)
+            .unwrap_or_else(|| util::lt(self.recv.nxt, tcp_header.sequence_number()));
+        newer.then(|| self.send.nxt.wrapping_add(REOPEN_ISS_GAP))
+    }
+
+    /// sets the maximum segment lifetime, which decides how long TIME-WAIT lasts.
+    pub fn set_msl(&mut self, msl: time::Duration) {
+        self.msl = msl;
+    }
+
+    fn enter_time_wait(&mut self) {
+        self.state = State::TimeWait;
+        self.timers.time_wait = Some(time::Instant::now());
+    }
+
     pub fn is_recv_closed(&self) -> bool {
         // TODO: completed, but not completely completed.
-        if let State::TimeWait = self.state {
-            true
-        } else {
-            false
-        }
+        matches!(self.state, State::TimeWait)
     }
 
-    /// a tiemr for unacked queue
+    // a tiemr for unacked queue
     // pub fn on_timer(&mut self, nic: &mut Iface) -> io::Result<Action> {
     //     if let State::FinWait2 | State::LastAck = self.state {
     //         return Ok(Action::Continue);
@@ -317,35 +1519,113 @@ impl TCB {
     // }
 
     pub fn on_tick(&mut self, nic: &mut Iface) -> io::Result<Action> {
-        //first, we figure out whether to retransmit
-        let waited_for = self
-            .timers
-            .send_times
-            .range(self.send.una..)
-            .next()
-            .map(|t| t.1.elapsed());
-
-        let should_retransmit = waited_for.and_then(|x| {
-            Some(x > time::Duration::from_secs(1) && x.as_secs_f64() > 1.5 * self.timers.srtt)
-        });
-        if should_retransmit.unwrap_or(false) {
-            self.write(nic, Request::ReTransmit).unwrap();
+        self.pace();
+        // active open: the SYN is sent at the first tick.
+        if self.state == State::SynSent && self.retransmission.is_empty() {
+            self.write(nic, Request::SYN)?;
             return Ok(Action::Continue);
         }
 
-        // then, we send unsent data if there is any.
-        let mut req = None;
-        match self.state {
-            State::FinWait2 => return Ok(Action::Continue),
-            State::FinWait1 => {
-                if self.closed_at.is_none() {
-                    self.closed_at = Some(self.send.una);
-                    req = Some(Request::FIN);
+        // no data has been sent in time to carry the delayed ACK, it goes on its own, and so does
+        // a window update after a read.
+        if self.delayed_ack.expired() || self.window_update {
+            self.write(nic, Request::ACK)?;
+        }
+        if let Some(size) = self.autotune.on_idle(self.recv_buffer) {
+            debug!("receive buffer idle, shrinking it to {} bytes", size);
+            self.recv_buffer = size;
+        }
+
+        // the peer has closed its window: the persist timer takes over from the retransmission
+        // timer, and the window is probed until it opens, RFC 9293 3.8.6.1. A pending FIN waits
+        // for the window as well.
+        let waiting = self.flight_size() > 0 || self.unsent() > 0 || self.fin_pending();
+        if self.send.wnd == 0 && waiting && !self.is_connecting() {
+            self.timers.stop();
+            if self.timers.persist_expired() {
+                if self.probes_exhausted() {
+                    debug!("{} window probes unanswered, aborting", self.window_probes);
+                    self.send_rst(nic)?;
+                    self.error = Some(io::ErrorKind::TimedOut);
+                    self.state = State::Closed;
+                    return Ok(Action::Close);
                 }
+                debug!("persist timer expired, probing the zero window");
+                self.probe(nic)?;
+                self.window_probes += 1;
+                self.timers.stop();
+                self.timers.on_probe();
+            } else {
+                self.timers.start_persist();
+            }
+            if let Some(timeout) = self.user_timeout {
+                let since = std::cmp::max(self.last_recv, self.last_progress);
+                self.timers.clamp(since + timeout);
+            }
+            return Ok(Action::Continue);
+        }
+        if self.timers.stop_persist() && self.flight_size() > 0 {
+            self.timers.restart();
+        }
+        // the timer expires when the user timeout does, not after a whole backed off RTO. The
+        // SYN of an active open is bounded by SYN_RETRIES instead.
+        if let Some(timeout) = self.user_timeout.filter(|_| self.state != State::SynSent) {
+            self.timers.clamp(self.last_progress + timeout);
+        }
+
+        //first, we figure out whether to retransmit the oldest unacknowledged segment.
+        // RFC 6298 (5.4) - (5.6)
+        if self.timers.expired() {
+            let retransmits = self.retransmission.front().map_or(0, |seg| seg.retransmits);
+            if self.state == State::SynSent && retransmits >= SYN_RETRIES {
+                debug!("SynSent: no response for our SYN, giving up");
+                self.error = Some(io::ErrorKind::TimedOut);
+                self.state = State::Closed;
+                return Ok(Action::Close);
+            }
+            if self.state != State::SynSent && self.retries_exhausted(retransmits) {
+                debug!("no ACK after {} retransmissions, aborting", retransmits);
+                self.send_rst(nic)?;
+                self.error = Some(io::ErrorKind::TimedOut);
+                self.state = State::Closed;
+                return Ok(Action::Close);
+            }
+            if retransmits + 1 >= self.r1 && !self.trouble {
+                info!(
+                    "{} retransmissions without an ACK, the path may be broken",
+                    self.r1
+                );
+                self.trouble = true;
             }
+            self.timers.backoff();
+            self.cc.on_timeout(self.flight_size());
+            self.dup_acks = 0;
+            self.limited_transmit = 0;
+            // RFC 6582 4: the data up to SND.NXT is retransmitted, starting at SND.UNA
+            self.rto_recover = Some(self.send.nxt);
+            self.scoreboard.high_rxt = None;
+            self.retransmit_lost(nic)?;
+            self.timers.restart();
+            debug!("retransmission timer expired, rto: {:?}", self.timers.rto);
+            return Ok(Action::Continue);
+        }
+        if let State::SynSent = self.state {
+            return Ok(Action::Continue);
+        }
+
+        if self.keepalive_expired(nic)? {
+            debug!("keepalive: no answer from the peer, aborting");
+            self.error = Some(io::ErrorKind::TimedOut);
+            self.state = State::Closed;
+            return Ok(Action::Close);
+        }
+
+        // then, we send unsent data if there is any, as much as the windows allow.
+        match self.state {
+            State::FinWait2 => return Ok(Action::Continue),
             State::TimeWait => {
-                //FIXME: set correct MSL.
-                if waited_for.expect("timer error in TimeWait") >= time::Duration::from_secs(2) {
+                let entered = self.timers.time_wait.expect("timer error in TimeWait");
+                if entered.elapsed() >= 2 * self.msl {
                     debug!("timewait ends");
                     return Ok(Action::Close);
                 } else {
@@ -355,38 +1635,92 @@ impl TCB {
             _ => {}
         }
 
-        let allowed = self.send.wnd as usize - self.outgoing.len();
-        match allowed {
-            // FIN wont' be sent if the allowed wnd is zero
-            0 => {
-                if let Some(Request::FIN) = req {
-                    self.closed_at = None;
-                };
-                return Ok(Action::Continue);
-            }
-            _ => {
-                let send = std::cmp::min(self.outgoing.len(), allowed);
-                if send <= allowed && !self.closed && !self.outgoing.is_empty() {
-                    req = Some(Request::ACK);
-                }
-            }
-        };
-        if req.is_some() {
+        // one segment of at most MSS bytes at a time, until the windows are full.
+        // FIN wont' be sent if the allowed wnd is zero
+        while self.send_window() > 0 {
+            let req = match self.state {
+                State::FinWait1 | State::LastAck if self.closed_at.is_none() => Request::FIN,
+                _ if !self.closed && self.unsent() > 0 && self.nagle_allows() => Request::ACK,
+                _ => break,
+            };
             debug!("send for req type: {:?}", req);
-            self.write(nic, req.unwrap())
-                .expect("on_tick: sending failed");
+            let len = self.write(nic, req).expect("on_tick: sending failed");
+            if len == 0 && self.closed_at.is_none() {
+                break;
+            }
+        }
+        // the rate samples taken while there is nothing to send do not tell the bandwidth.
+        if self.unsent() == 0 && self.flight_size() < self.cc.cwnd() {
+            self.delivery.set_app_limited(self.flight_size());
         }
         Ok(Action::Continue)
     }
 
+    /// whether the connection should be aborted rather than retransmitting a segment which has
+    /// been retransmitted `retransmits` times: R2 is reached, or the user timeout has expired.
+    fn retries_exhausted(&self, retransmits: u32) -> bool {
+        let timed_out = self
+            .user_timeout
+            .is_some_and(|timeout| self.last_progress.elapsed() >= timeout);
+        retransmits >= self.r2 || timed_out
+    }
+
+    /// whether the connection should be aborted rather than probing the zero window again: R2
+    /// probes have gone unanswered, or nothing has arrived from the peer for the user timeout.
+    fn probes_exhausted(&self) -> bool {
+        let timed_out = self.user_timeout.is_some_and(|timeout| {
+            self.last_recv.elapsed() >= timeout && self.last_progress.elapsed() >= timeout
+        });
+        self.window_probes >= self.r2 || timed_out
+    }
+
+    /// sends a keepalive probe when it is due, which is a segment carrying SND.NXT - 1 that the
+    /// peer has to acknowledge. Returns whether all the probes have gone unanswered.
+    fn keepalive_expired(&mut self, nic: &mut Iface) -> io::Result<bool> {
+        let keepalive = match self.keepalive {
+            Some(keepalive) => keepalive,
+            None => return Ok(false),
+        };
+        // only an idle connection is probed, otherwise the retransmissions find a dead peer.
+        let idle = matches!(self.state, State::Estab | State::CloseWait)
+            && self.flight_size() == 0
+            && self.unsent() == 0;
+        if !idle {
+            return Ok(false);
+        }
+        let due = keepalive.idle + keepalive.interval * self.keepalive_probes;
+        if self.last_recv.elapsed() < due {
+            return Ok(false);
+        }
+        if self.keepalive_probes >= keepalive.probes {
+            return Ok(true);
+        }
+        debug!("keepalive: probe {}", self.keepalive_probes + 1);
+        let seqn = self.send.nxt.wrapping_sub(1);
+        self.send_segment(nic, seqn, 0)?;
+        self.keepalive_probes += 1;
+        Ok(false)
+    }
+
+    /// sends a challenge ACK of RFC 5961, unless the interface has used up its rate.
+    fn challenge_ack(
+        &mut self,
+        nic: &mut Iface,
+        challenge_acks: &mut ChallengeAcks,
+    ) -> io::Result<()> {
+        if challenge_acks.take() {
+            self.write(nic, Request::ACK)?;
+        } else {
+            debug!("challenge ACK rate limited");
+        }
+        Ok(())
+    }
+
     /// RFC 793 page 36
     pub fn send_rst(&mut self, nic: &mut Iface) -> io::Result<()> {
         // TODO: completed, but not completely completed.
-        match self.state {
-            State::SynRcvd => {
-                self.tcp_header.rst = true;
-            }
-            _ => {}
+        if self.state == State::SynRcvd {
+            self.tcp_header.rst = true;
         }
 
         self.write(nic, Request::RST).unwrap();
@@ -401,9 +1735,14 @@ impl TCB {
         nic: &mut Iface,
         tcp_header: etherparse::TcpHeaderSlice,
         data: &[u8],
+        challenge_acks: &mut ChallengeAcks,
     ) -> io::Result<Action> {
         let ackn = tcp_header.acknowledgment_number();
         let seqn = tcp_header.sequence_number();
+        let options = options::Options::parse(tcp_header.options_iterator());
+        self.last_recv = time::Instant::now();
+        self.keepalive_probes = 0;
+        self.window_probes = 0;
 
         debug!(
             "on segmenting, self state: {:?} -> syn: {:?}, fin {:?}, ack: {:?}, rst: {:?}, seqn: {:?}",
@@ -429,15 +1768,75 @@ impl TCB {
                 }
             }
             State::SynSent => {
-                // we didn't implement active open yet.
-                unimplemented!();
+                // RFC 793 page 66
+                // first check the ACK bit
+                if tcp_header.ack()
+                    && (util::le(ackn, self.send.iss) || util::lt(self.send.nxt, ackn))
+                {
+                    if !tcp_header.rst() {
+                        // <SEQ=SEG.ACK><CTL=RST>
+                        let nxt = std::mem::replace(&mut self.send.nxt, ackn);
+                        self.write(nic, Request::RST)?;
+                        self.send.nxt = nxt;
+                    }
+                    return Ok(Action::Continue);
+                }
+
+                // second check the RST bit, the ACK is acceptable if there is one.
+                if tcp_header.rst() {
+                    if tcp_header.ack() {
+                        debug!("SynSent: connection refused");
+                        self.error = Some(io::ErrorKind::ConnectionRefused);
+                        self.state = State::Closed;
+                        return Ok(Action::Close);
+                    }
+                    return Ok(Action::Continue);
+                }
+
+                // third check security and precedence, NOT DONE
+                // fourth check the SYN bit
+                if !tcp_header.syn() {
+                    return Ok(Action::Continue);
+                }
+                self.recv.irs = seqn;
+                self.recv.nxt = seqn.wrapping_add(1);
+                self.sack_permitted &= options.sack_permitted;
+                self.negotiate_window_scale(options.window_scale);
+                self.timestamps.on_syn(options.timestamp);
+                self.negotiate_mss(options.mss);
+                self.send.wnd = tcp_header.window_size() as u32;
+                self.send.max_wnd = self.send.wnd;
+                self.send.wl1 = seqn;
+                self.send.wl2 = ackn;
+                self.tcp_header.ack = true;
+                if tcp_header.ack() {
+                    self.acknowledge(ackn, options.timestamp);
+                }
+
+                if util::lt(self.send.iss, self.send.una) {
+                    debug!("SynSent: our SYN is ACKed, irs: {:?}", self.recv.irs);
+                    self.state = State::Estab;
+                    self.write(nic, Request::ACK)?;
+                    return Ok(Action::Estab);
+                }
+
+                // simultaneous open, send <SEQ=ISS><ACK=RCV.NXT><CTL=SYN,ACK>
+                debug!("SynSent: simultaneous open, enter SynRcvd");
+                self.state = State::SynRcvd;
+                self.write(nic, Request::ReTransmit)?;
+                return Ok(Action::Continue);
             }
             State::Listen => {
                 if tcp_header.ack() {
                     self.send_rst(nic).unwrap();
                 }
                 if tcp_header.syn() {
-                    let tcb = TCB::new(self.ip_header.clone(), self.tcp_header.clone());
+                    let tcb = TCB::new(
+                        self.ip_header.clone(),
+                        self.tcp_header.clone(),
+                        self.send.iss,
+                        self.recv_buffer,
+                    );
                     let _o = std::mem::replace(self, tcb);
                     self.tcp_header.syn = true;
                     self.tcp_header.ack = true;
@@ -456,17 +1855,42 @@ impl TCB {
                 // RFC793 page 69
 
                 // first check sequence number
-                if let false = self.check_seq(data, &tcp_header) {
+                if !self.check_seq(data, &tcp_header, options.timestamp) {
                     debug!("seqn: {:?} -> sequence number invalid", seqn);
+                    // RFC 5961 3.2: a RST outside the window is dropped silently
                     if tcp_header.rst() {
-                        return Ok(Action::Close);
+                        return Ok(Action::Continue);
+                    }
+                    if tcp_header.syn() {
+                        self.challenge_ack(nic, challenge_acks)?;
+                        return Ok(Action::Continue);
+                    }
+                    // an old duplicate is reported with D-SACK, RFC 2883
+                    let end = seqn.wrapping_add(data.len() as u32);
+                    if self.sack_permitted && util::le(end, self.recv.nxt) {
+                        self.sack.on_duplicate(seqn, end);
                     }
                     self.write(nic, Request::ACK).unwrap();
                     return Ok(Action::Continue);
                 }
 
+                self.timestamps.update(seqn, options.timestamp);
+
                 // second check the RST bit
                 if tcp_header.rst() {
+                    // RFC 1337: a RST must not cut TIME-WAIT short, or an old duplicate could
+                    // reach a new incarnation of the connection.
+                    if self.state == State::TimeWait {
+                        debug!("seqn: {:?} -> recv RST in TimeWait, ignored", seqn);
+                        return Ok(Action::Continue);
+                    }
+                    // RFC 5961 3.2: only a RST at exactly RCV.NXT resets the connection, any other
+                    // one in the window may be a blind guess and is answered with a challenge ACK.
+                    if seqn != self.recv.nxt {
+                        debug!("seqn: {:?} -> recv RST in the window, challenging", seqn);
+                        self.challenge_ack(nic, challenge_acks)?;
+                        return Ok(Action::Continue);
+                    }
                     // FIXME: Here we just close this connection when received RST, but RFC 793
                     // suggests TCP
                     // should be transfered to proper state.
@@ -477,11 +1901,12 @@ impl TCB {
 
                 // third check security and precedence, NOT DONE
                 // fourth check the SYN bit
+                // RFC 5961 4.2: a SYN is answered with a challenge ACK instead of a RST, the peer
+                // will reset the connection itself if it has really restarted.
                 if tcp_header.syn() {
-                    debug!("seqn: {:?} -> recv dup SYN", seqn);
-                    self.send_rst(nic).unwrap();
-                    self.state = State::Closed;
-                    return Ok(Action::Close);
+                    debug!("seqn: {:?} -> recv dup SYN, challenging", seqn);
+                    self.challenge_ack(nic, challenge_acks)?;
+                    return Ok(Action::Continue);
                 };
 
                 debug!(
@@ -499,16 +1924,16 @@ impl TCB {
                         return Ok(Action::Continue);
                     }
                     if let State::SynRcvd = self.state {
-                        if util::le(self.send.una, ackn) && util::le(ackn, self.send.nxt) {
+                        if util::lt(self.send.una, ackn) && util::le(ackn, self.send.nxt) {
+                            self.acknowledge(ackn, options.timestamp);
                             self.state = State::Estab;
-                            self.send.una = ackn;
                             if tcp_header.fin() {
                                 self.closed = true;
                                 self.state = State::LastAck;
                                 self.write(nic, Request::FIN).unwrap();
                             }
                             debug!("state from SynRcvd to Estab");
-                            return Ok(Action::Continue);
+                            return Ok(Action::Estab);
                         } else {
                             self.send_rst(nic).unwrap();
                             return Ok(Action::Close);
@@ -518,18 +1943,21 @@ impl TCB {
                     | State::CloseWait
                     | State::FinWait1
                     | State::FinWait2
-                    | State::Closing = self.state
+                    | State::Closing
+                    | State::LastAck = self.state
                     {
-                        // ackn too small, ignore
-                        if util::lt(ackn, self.send.una) {
+                        // RFC 5961 5.2: the ACK must be in [SND.UNA - MAX.SND.WND, SND.NXT], the
+                        // segment is dropped with a challenge ACK otherwise.
+                        let oldest = self.send.una.wrapping_sub(self.send.max_wnd);
+                        if util::lt(ackn, oldest) || util::lt(self.send.nxt, ackn) {
+                            debug!("ackn: {:?} -> unacceptable ACK, challenging", ackn);
+                            self.challenge_ack(nic, challenge_acks)?;
                             return Ok(Action::Continue);
                         }
-                        // ackn too large, send ack and return
-                        // FIXME: which ackn should be sent?
-                        if util::lt(self.send.nxt, ackn) {
-                            self.write(nic, Request::ACK).unwrap();
-                            // self.write(nic, self.send.nxt, 0).unwrap();
-                            return Ok(Action::Continue);
+
+                        // an old duplicate ACK below SND.UNA is ignored, the text still counts
+                        if self.sack_permitted && util::le(self.send.una, ackn) {
+                            self.scoreboard.update(ackn, self.send.nxt, &options.sack);
                         }
 
                         // ackn just fits
@@ -538,74 +1966,130 @@ impl TCB {
                             // 2. Any segments on the retransmission queue which are thereby
                             //    entirely acknowledged are removed
                             // NOTE: send.nxt will be updated in the next steps
-                            // NOTE: only use clear() in ideal situation.So we do an assert.
-                            // assert_eq!(self.unacked.len(), ackn.wrapping_sub(self.send.una));
-                            if util::lt(self.send.wl1, seqn)
-                                || (self.send.wl1 == seqn && util::le(self.send.wl2, ackn))
+                            self.update_window(&tcp_header);
+
+                            let recovering = self.cc.in_recovery();
+                            self.acknowledge(ackn, options.timestamp);
+                            self.dup_acks = 0;
+                            self.limited_transmit = 0;
+                            // a partial acknowledgment in fast recovery: the next hole is
+                            // retransmitted at once, RFC 6582 3.2 (5)
+                            if recovering
+                                && self.cc.in_recovery()
+                                && !self.retransmit_hole(nic).unwrap()
                             {
-                                self.send.wnd = tcp_header.window_size();
-                                self.send.wl1 = seqn;
-                                self.send.wl2 = ackn;
+                                self.write(nic, Request::ReTransmit).unwrap();
+                            }
+                            // after a timeout, more of the lost data goes as cwnd opens
+                            if self.rto_recover.is_some() {
+                                self.retransmit_lost(nic)?;
                             }
-
-                            self.outgoing.clear();
-
-                            self.update_srtt(ackn).unwrap();
-
-                            self.send.una = ackn;
-                            self.timers.send_times.remove(&self.send.una);
 
                             // NOTE: do not return and do not wirte anything right now, because
                             // there may be a FIN.
+                        } else if ackn == self.send.una
+                            && self.flight_size() > 0
+                            && data.is_empty()
+                            && !tcp_header.syn()
+                            && !tcp_header.fin()
+                            && self.scaled_window(&tcp_header) == self.send.wnd
+                            // the answers to window probes are not duplicate ACKs
+                            && self.send.wnd > 0
+                        {
+                            self.on_dup_ack(nic);
+                        } else if ackn == self.send.una {
+                            // a window update, which is what reopens a zero window
+                            self.update_window(&tcp_header);
                         }
                     }
 
                     // This ACK is for our FIN which was sent with payloads together.
-                    if self.closed {
-                        // if self.closed_at.expect("why closed_at not set?") == ackn {
-                        if self.closed_at.is_some() {
-                            match self.state {
-                                State::FinWait1 => self.state = State::FinWait2,
-                                State::FinWait2 => {}
-                                State::Closing => self.state = State::TimeWait,
-                                State::LastAck => {
-                                    self.state = State::Closed;
-                                    debug!("seqn: {:?}, got ack for our FIN, now perish", seqn);
-                                    return Ok(Action::Close);
-                                }
-                                State::TimeWait => {
-                                    // Here we received the retransmission queue of FIN, we should ACK this FIN
-                                    self.write(nic, Request::ACK).unwrap();
-                                    // self.write(nic, self.send.nxt, 0).unwrap();
-                                    self.timers
-                                        .send_times
-                                        .insert(self.send.una, time::Instant::now());
-                                    return Ok(Action::Continue);
-                                }
-                                _ => unreachable!(),
+                    if self.closed && self.fin_acked() {
+                        match self.state {
+                            State::FinWait1 => self.state = State::FinWait2,
+                            State::FinWait2 => {}
+                            State::Closing => self.enter_time_wait(),
+                            State::LastAck => {
+                                self.state = State::Closed;
+                                debug!("seqn: {:?}, got ack for our FIN, now perish", seqn);
+                                return Ok(Action::Close);
                             }
+                            State::TimeWait => {
+                                // Here we received the retransmission queue of FIN, we should ACK this FIN
+                                self.write(nic, Request::ACK).unwrap();
+                                // self.write(nic, self.send.nxt, 0).unwrap();
+                                self.timers.time_wait = Some(time::Instant::now());
+                                return Ok(Action::Continue);
+                            }
+                            _ => unreachable!(),
                         }
                     }
                 };
 
                 let mut req: Option<Request> = None;
                 let mut act: Option<Action> = None;
-                // sixth check the URG bit, NOT DONE
+                // sixth check the URG bit
+                if tcp_header.urg() {
+                    if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+                        self.urgent
+                            .on_pointer(seqn, tcp_header.urgent_pointer(), self.recv.nxt);
+                    }
+                }
                 // seventh, process the segment text
+                let mut fin = tcp_header.fin();
                 if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+                    if fin {
+                        self.assembler.fin = Some(seqn.wrapping_add(data.len() as u32));
+                    }
                     if !data.is_empty() {
-                        // If incoming has no space for this data, an error should return but we
-                        // still need to ack this data. (RFC 793 page 58). Since our incoming is
-                        // growable, we will not handle it.
-                        self.incoming.extend(&data[..]);
-                        self.recv.nxt = seqn.wrapping_add(data.len() as u32);
-                        req = Some(Request::ACK);
+                        // The window never goes beyond the free space of the receive buffer, so
+                        // incoming always has room for the data inside it.
+                        // Data is only moved into incoming when it is contiguous to RCV.NXT, and
+                        // we always ack RCV.NXT, so an out of order segment causes a dup ACK.
+                        // out-of-order data, and data which fills a hole, are acknowledged at
+                        // once, RFC 5681 4.2
+                        let immediate =
+                            seqn != self.recv.nxt || !self.assembler.segments.is_empty();
+                        // the TSecr of in-order data gives the receiver side RTT for auto-tuning
+                        if !immediate {
+                            if let Some(rtt) = self.timestamps.rtt(options.timestamp) {
+                                self.autotune.on_rtt(rtt);
+                            }
+                        }
+                        if self.sack_permitted {
+                            let end = seqn.wrapping_add(data.len() as u32);
+                            if util::lt(seqn, self.recv.nxt) {
+                                self.sack.on_duplicate(seqn, self.recv.nxt);
+                            } else if let Some((s, e)) = self.assembler.overlap(seqn, end) {
+                                self.sack.on_duplicate(s, e);
+                            }
+                            if util::lt(self.recv.nxt, seqn) {
+                                self.sack.on_out_of_order(seqn);
+                            }
+                        }
+                        self.assembler
+                            .insert(self.recv.nxt, self.recv.wnd, seqn, data);
+                        while let Some(d) = self.assembler.pop(self.recv.nxt) {
+                            self.recv.nxt = self.recv.nxt.wrapping_add(d.len() as u32);
+                            // the right edge of the window stays where it is
+                            self.recv.wnd = self.recv.wnd.saturating_sub(d.len() as u32);
+                            self.incoming.extend(d);
+                        }
+                        self.urgent.on_delivered(&mut self.incoming, self.recv.nxt);
+                        if self.delayed_ack.on_data(data.len(), immediate) {
+                            req = Some(Request::ACK);
+                        }
+                    }
+                    // a FIN can only be processed after all the data in front of it.
+                    fin = self.assembler.fin == Some(self.recv.nxt);
+                    if fin {
+                        self.assembler.fin = None;
                     }
                 }
 
                 // eighth check the FIN bit
                 // here we just only adjust the state.
-                if tcp_header.fin() {
+                if fin {
                     if let State::Closed | State::Listen | State::SynSent = self.state {
                         return Ok(Action::Continue);
                     }
@@ -619,85 +2103,508 @@ impl TCB {
                             self.state = State::LastAck;
                             req = Some(Request::FIN);
                             act = Some(Action::Continue);
-                            self.closed_at = Some(self.send.una);
                         }
                         State::FinWait1 => {
-                            if self.closed_at.expect("why closed_at not set?")
-                                == ackn.wrapping_sub(1)
-                            {
-                                self.state = State::TimeWait;
-                                self.timers
-                                    .send_times
-                                    .insert(self.send.una, time::Instant::now());
+                            if self.fin_acked() {
+                                self.enter_time_wait();
                             } else {
                                 self.state = State::Closing;
                             }
                         }
-                        State::FinWait2 => {
-                            self.state = State::TimeWait;
-                            self.timers
-                                .send_times
-                                .insert(self.send.una, time::Instant::now());
-                        }
+                        State::FinWait2 => self.enter_time_wait(),
                         State::TimeWait => {
-                            self.timers
-                                .send_times
-                                .insert(self.send.una, time::Instant::now());
+                            self.timers.time_wait = Some(time::Instant::now());
                         }
                         _ => {}
                     }
                 }
 
                 debug!("seqn: {:?} -> now state: {:?}", seqn, self.state);
-                if req.is_some() {
-                    self.write(nic, req.unwrap()).unwrap();
+                if let Some(req) = req {
+                    self.write(nic, req).unwrap();
                 }
-                if act.is_some() {
-                    return Ok(act.unwrap());
+                if let Some(act) = act {
+                    return Ok(act);
                 }
             }
         }
 
-        return Ok(Action::Read);
+        Ok(Action::Read)
     }
 
-    fn check_seq(&mut self, data: &[u8], tcp_header: &etherparse::TcpHeaderSlice) -> bool {
-        let in_wnd = util::segment_valid(
-            self.recv.nxt,
-            tcp_header.sequence_number(),
-            self.recv.nxt.wrapping_add(self.recv.wnd as u32),
-        );
-        if data.len() == 0 {
+    /// RFC 793 page 69, a segment is acceptable if its beginning or its end falls in the receive
+    /// window, so a retransmission which overlaps with received data is not dropped.
+    fn check_seq(
+        &mut self,
+        data: &[u8],
+        tcp_header: &etherparse::TcpHeaderSlice,
+        timestamp: Option<(u32, u32)>,
+    ) -> bool {
+        let seqn = tcp_header.sequence_number();
+        // PAWS, RFC 7323 5.3
+        if !tcp_header.rst() && self.timestamps.is_old(timestamp) {
+            debug!("PAWS: old duplicate {:?}", seqn);
+            return false;
+        }
+        let wnd_end = self.recv.nxt.wrapping_add(self.recv.wnd);
+        let in_wnd = util::segment_valid(self.recv.nxt, seqn, wnd_end);
+        if data.is_empty() {
             if self.recv.wnd == 0 {
-                return tcp_header.sequence_number() == self.recv.nxt;
+                seqn == self.recv.nxt
             } else {
-                return in_wnd;
+                in_wnd
             }
         } else {
-            if self.recv.wnd > 0 {
-                return in_wnd;
-            } else {
-                return false;
+            let last = seqn.wrapping_add(data.len() as u32 - 1);
+            self.recv.wnd > 0 && (in_wnd || util::segment_valid(self.recv.nxt, last, wnd_end))
+        }
+    }
+
+    /// Counts a duplicate ACK, RFC 5681 3.2. The first two let new data out with limited transmit,
+    /// the third one retransmits the first unacknowledged segment and starts fast recovery, and
+    /// the later ones inflate the window of the recovery.
+    fn on_dup_ack(&mut self, nic: &mut Iface) {
+        self.dup_acks += 1;
+        debug!("dup ack {} for {}", self.dup_acks, self.send.una);
+        if self.cc.in_recovery() {
+            self.cc.on_dup_ack();
+            if self.retransmit_hole(nic).unwrap() {
+                return;
             }
+        } else if self.dup_acks < DUP_ACK_THRESHOLD {
+            self.limited_transmit = self.dup_acks as usize * self.mss;
+        } else if self.dup_acks == DUP_ACK_THRESHOLD && !self.recovering_from_timeout() {
+            self.limited_transmit = 0;
+            self.cc.on_loss(self.send.nxt, self.flight_size());
+            let una = self.send.una;
+            self.scoreboard.high_rxt = self.retransmit(nic, una).unwrap().map(|(end, _)| end);
+            return;
+        }
+        if !self.closed && self.unsent() > 0 && self.send_window() > 0 {
+            self.write(nic, Request::ACK).unwrap();
         }
     }
 
-    /// reset timeout and update srtt
-    fn update_srtt(&mut self, ackn: u32) -> io::Result<()> {
-        let acked = std::mem::replace(&mut self.timers.send_times, BTreeMap::new());
-        let una = self.send.una;
-        self.timers
-            .send_times
-            .extend(acked.into_iter().filter_map(|(seq, sent)| {
-                if util::segment_valid(una, seq, ackn) {
-                    // SRTT = ( ALPHA * SRTT ) + ((1-ALPHA) * RTT)
-                    self.timers.srtt =
-                        0.8 * self.timers.srtt + (1.0 - 0.8) * sent.elapsed().as_secs_f64();
-                    None
-                } else {
-                    Some((seq, sent))
+    /// whether the data sent before the last timeout is still being acknowledged. Its duplicate
+    /// ACKs do not start another fast retransmit, RFC 6582 4.
+    fn recovering_from_timeout(&self) -> bool {
+        self.rto_recover
+            .is_some_and(|recover| util::lt(self.send.una, recover))
+    }
+
+    /// Advances SND.UNA to `ackn`. Segments on the retransmission queue which are thereby entirely
+    /// acknowledged are removed, and a segment which is partially acknowledged is trimmed, so
+    /// only its unacknowledged range will be retransmitted. The acknowledged data is dropped from
+    /// outgoing. The round trip time is measured with the echoed `timestamp` if timestamps are
+    /// used, or with the send time of a segment which was never retransmitted.
+    fn acknowledge(&mut self, ackn: u32, timestamp: Option<(u32, u32)>) {
+        let newly_acked = ackn.wrapping_sub(self.send.una) as usize;
+        let now = time::Instant::now();
+        self.delivery.on_delivered(newly_acked, now);
+        let mut acked = 0;
+        // RFC 7323 4.1, TSecr gives a valid sample even for retransmitted segments.
+        if let Some(rtt) = self.timestamps.rtt(timestamp) {
+            self.timers.sample(rtt);
+            self.cc.on_rtt(rtt);
+        }
+        while let Some(seg) = self.retransmission.front_mut() {
+            if util::le(seg.seqn.wrapping_add(seg.len), ackn) {
+                acked += seg.data_len();
+                self.delivery.on_acked(seg.tx, seg.sent);
+                // Karn's algorithm: the round trip time of a retransmitted segment is ambiguous.
+                if seg.retransmits == 0 && !self.timestamps.enabled {
+                    let rtt = seg.sent.elapsed();
+                    self.timers.sample(rtt);
+                    self.cc.on_rtt(rtt);
+                } else if seg.retransmits > 0 && seg.syn && self.timers.srtt.is_none() {
+                    self.timers.rto = SYN_RETRANSMITTED_RTO;
                 }
-            }));
-        Ok(())
+                self.retransmission.pop_front();
+                continue;
+            }
+            if util::lt(seg.seqn, ackn) {
+                let mut n = ackn.wrapping_sub(seg.seqn);
+                seg.seqn = ackn;
+                seg.len -= n;
+                if seg.syn {
+                    seg.syn = false;
+                    n -= 1;
+                }
+                acked += n as usize;
+            }
+            break;
+        }
+        let acked = std::cmp::min(acked, self.outgoing.len());
+        drop(self.outgoing.drain(..acked));
+        self.send.una = ackn;
+        self.last_progress = now;
+        if self.snd_up.is_some_and(|up| util::le(up, ackn)) {
+            self.snd_up = None;
+        }
+        if self.trouble {
+            info!("the retransmissions are acknowledged, the path has recovered");
+            self.trouble = false;
+        }
+        self.scoreboard.acknowledge(ackn);
+        self.cc.on_ack(&congestion::Ack {
+            ackn,
+            acked: newly_acked,
+            flight: self.flight_size(),
+            delivered: self.delivery.delivered(),
+            rate: self.delivery.sample(),
+            now,
+        });
+
+        // RFC 6298 (5.2) and (5.3)
+        if self.retransmission.is_empty() {
+            self.timers.stop();
+        } else {
+            self.timers.restart();
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            srtt: self.timers.srtt,
+            rttvar: self.timers.rttvar,
+            rto: self.timers.rto,
+            congestion: self.cc.name(),
+            cwnd: self.cc.cwnd(),
+            ssthresh: self.cc.ssthresh(),
+            congestion_state: self.cc.describe(),
+            trouble: self.trouble,
+        }
+    }
+
+    /// marks all the data in outgoing as urgent, up to its last byte.
+    pub fn mark_urgent(&mut self) {
+        self.snd_up = Some(self.send.una.wrapping_add(self.outgoing.len() as u32));
+    }
+
+    /// takes the urgent byte which was received out of band.
+    pub fn read_urgent(&mut self) -> Option<u8> {
+        self.urgent.take()
+    }
+
+    /// whether the next byte to read is at the urgent mark.
+    pub fn at_urgent_mark(&self) -> bool {
+        self.urgent.at_mark()
+    }
+
+    /// leaves the urgent bytes in the stream instead of taking the last one out of band.
+    pub fn set_urgent_inline(&mut self, inline: bool) {
+        self.urgent.inline = inline;
+    }
+
+    /// how many bytes of incoming a read may take, a read stops at the urgent mark.
+    pub(crate) fn readable(&self) -> usize {
+        self.urgent.readable(self.incoming.len())
+    }
+
+    /// the application has read `n` bytes from incoming.
+    pub(crate) fn on_read(&mut self, n: usize) {
+        drop(self.incoming.drain(..n));
+        self.urgent.on_read(n);
+        if let Some(size) = self.autotune.on_read(n, self.recv_buffer, self.timers.srtt) {
+            debug!("receive buffer grows to {} bytes", size);
+            self.recv_buffer = size;
+        }
+        if self.open_window() {
+            self.window_update = true;
+        }
+    }
+
+    /// sets the size of the receive buffer, at most 16 MiB. A smaller buffer shrinks the window
+    /// to the free space in it. Auto-tuning starts from this size and goes back to it when the
+    /// connection is idle.
+    pub fn set_recv_buffer(&mut self, size: usize) {
+        self.recv_buffer = std::cmp::min(size, MAX_RECV_BUFFER);
+        self.autotune.set_base(self.recv_buffer);
+        let free = self.recv_buffer.saturating_sub(self.incoming.len()) as u32;
+        self.recv.wnd = std::cmp::min(self.recv.wnd, free);
+    }
+
+    /// sets the size the receive buffer may grow to by auto-tuning, None turns it off.
+    pub fn set_recv_buffer_max(&mut self, max: Option<usize>) {
+        self.autotune.max = max.map(|max| std::cmp::min(max, MAX_RECV_BUFFER));
+    }
+
+    /// sets the retransmission thresholds: after `r1` retransmissions of a segment the path is
+    /// reported in trouble, after `r2` the connection is aborted.
+    pub fn set_retries(&mut self, r1: u32, r2: u32) {
+        self.r1 = r1;
+        self.r2 = std::cmp::max(r1, r2);
+    }
+
+    /// sets how long sent data may stay unacknowledged before the connection is aborted, None
+    /// for no limit but R2.
+    pub fn set_user_timeout(&mut self, timeout: Option<time::Duration>) {
+        self.user_timeout = timeout;
+    }
+
+    /// turns the keepalive on with the given timing, or off with None.
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self.keepalive = keepalive;
+        self.keepalive_probes = 0;
+    }
+
+    /// sets the size of the send buffer. Data queued already stays when it is made smaller.
+    pub fn set_send_buffer(&mut self, size: usize) {
+        self.send_buffer = size;
+    }
+
+    /// how many more bytes the send buffer takes.
+    pub(crate) fn send_space(&self) -> usize {
+        self.send_buffer.saturating_sub(self.outgoing.len())
+    }
+
+    /// turns Nagle's algorithm off, so small segments are sent even when data is in flight.
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = nodelay;
+    }
+
+    pub fn nodelay(&self) -> bool {
+        self.nodelay
+    }
+
+    /// acknowledges every segment at once instead of delaying the ACKs.
+    pub fn set_quickack(&mut self, quickack: bool) {
+        self.delayed_ack.quickack = quickack;
+    }
+
+    /// sets how long an ACK may be delayed, which is kept between 40 and 200 milliseconds.
+    pub fn set_ack_delay(&mut self, delay: time::Duration) {
+        self.delayed_ack.delay = delay.clamp(ACK_DELAY_RANGE.0, ACK_DELAY_RANGE.1);
+    }
+
+    /// switches the congestion control algorithm, the new one starts from its initial state.
+    pub fn set_congestion(&mut self, algorithm: congestion::Algorithm) {
+        self.algorithm = algorithm;
+        self.cc = algorithm.build(self.mss);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queued(assembler: &Assembler) -> Vec<(u32, Vec<u8>)> {
+        assembler.segments.iter().cloned().collect()
+    }
+
+    #[test]
+    fn assembler_merges_overlapping_and_adjacent_data() {
+        let mut assembler = Assembler::default();
+        assembler.insert(0, 100, 10, &[1; 10]);
+        assembler.insert(0, 100, 40, &[4; 5]);
+        // overlaps the first range, the bytes queued already are kept
+        assembler.insert(0, 100, 15, &[2; 10]);
+        assert_eq!(
+            queued(&assembler),
+            vec![(10, [&[1; 10][..], &[2; 5]].concat()), (40, vec![4; 5])]
+        );
+        // adjacent to both ranges, which become one
+        assembler.insert(0, 100, 25, &[3; 15]);
+        assert_eq!(
+            queued(&assembler),
+            vec![(10, [&[1; 10][..], &[2; 5], &[3; 15], &[4; 5]].concat())]
+        );
+        assert_eq!(assembler.pop(0), None);
+        assert_eq!(assembler.pop(10).map(|d| d.len()), Some(35));
+        assert!(assembler.segments.is_empty());
+    }
+
+    #[test]
+    fn assembler_keeps_only_the_window() {
+        let mut assembler = Assembler::default();
+        // starts before RCV.NXT and ends beyond the window
+        assembler.insert(100, 10, 95, &[7; 20]);
+        assert_eq!(queued(&assembler), vec![(100, vec![7; 10])]);
+        // entirely outside
+        assembler.insert(100, 10, 80, &[1; 20]);
+        assembler.insert(100, 10, 110, &[1; 5]);
+        assert_eq!(queued(&assembler), vec![(100, vec![7; 10])]);
+    }
+
+    #[test]
+    fn assembler_handles_sequence_wraparound() {
+        let nxt = u32::MAX - 4;
+        let mut assembler = Assembler::default();
+        assembler.insert(nxt, 100, nxt.wrapping_add(3), &[2; 6]);
+        assert_eq!(assembler.overlap(nxt, 2), Some((nxt.wrapping_add(3), 2)));
+        assembler.insert(nxt, 100, nxt, &[1; 4]);
+        assert_eq!(assembler.ranges(), vec![(nxt, 4)]);
+        assert_eq!(assembler.pop(nxt), Some([&[1; 3][..], &[2; 6]].concat()));
+    }
+
+    #[test]
+    fn timers_sample_rtt() {
+        let mut timers = Timers::default();
+        assert_eq!(timers.rto, INITIAL_RTO);
+        // the first sample, RFC 6298 (2.2)
+        timers.sample(time::Duration::from_secs(2));
+        assert_eq!(timers.srtt, Some(time::Duration::from_secs(2)));
+        assert_eq!(timers.rttvar, time::Duration::from_secs(1));
+        assert_eq!(timers.rto, time::Duration::from_secs(6));
+        // RFC 6298 (2.3)
+        timers.sample(time::Duration::from_secs(4));
+        assert_eq!(timers.srtt, Some(time::Duration::from_millis(2250)));
+        assert_eq!(timers.rttvar, time::Duration::from_millis(1250));
+        assert_eq!(timers.rto, time::Duration::from_millis(7250));
+        // never below MIN_RTO
+        let mut timers = Timers::default();
+        timers.sample(time::Duration::from_millis(10));
+        assert_eq!(timers.rto, MIN_RTO);
+    }
+
+    #[test]
+    fn timers_backoff_up_to_max_rto() {
+        let mut timers = Timers::default();
+        timers.sample(time::Duration::from_secs(2));
+        timers.backoff();
+        assert_eq!(timers.rto, time::Duration::from_secs(12));
+        timers.backoff();
+        timers.backoff();
+        assert_eq!(timers.rto, time::Duration::from_secs(48));
+        timers.backoff();
+        assert_eq!(timers.rto, MAX_RTO);
+        // a new sample takes the backoff back
+        timers.sample(time::Duration::from_secs(2));
+        assert!(timers.rto < MAX_RTO);
+    }
+
+    #[test]
+    fn timers_clamp_to_a_deadline() {
+        let mut timers = Timers::default();
+        let now = time::Instant::now();
+        // a timer which is off stays off
+        timers.clamp(now);
+        assert_eq!((timers.expires, timers.persist), (None, None));
+        timers.backoff();
+        timers.start();
+        timers.clamp(now + time::Duration::from_secs(10));
+        assert!(timers.expires.unwrap() < now + time::Duration::from_secs(3));
+        timers.clamp(now);
+        assert!(timers.expired());
+        timers.stop();
+        timers.start_persist();
+        timers.clamp(now);
+        assert!(timers.persist_expired());
+    }
+
+    #[test]
+    fn delayed_ack_on_every_second_full_segment() {
+        let mut delayed_ack = DelayedAck::default();
+        assert!(!delayed_ack.on_data(1000, false));
+        let due = delayed_ack.due;
+        assert!(due.is_some());
+        // smaller segments count against the largest one seen
+        assert!(!delayed_ack.on_data(500, false));
+        assert_eq!(delayed_ack.due, due);
+        assert!(delayed_ack.on_data(500, false));
+        delayed_ack.on_sent();
+        assert_eq!((delayed_ack.due, delayed_ack.bytes), (None, 0));
+        // out-of-order data and quick ACKs are acknowledged at once
+        assert!(delayed_ack.on_data(100, true));
+        delayed_ack.on_sent();
+        delayed_ack.quickack = true;
+        assert!(delayed_ack.on_data(100, false));
+        // the held ACK goes when the delay is over
+        delayed_ack.quickack = false;
+        delayed_ack.on_sent();
+        delayed_ack.delay = time::Duration::ZERO;
+        assert!(!delayed_ack.on_data(100, false));
+        assert!(delayed_ack.expired());
+    }
+
+    #[test]
+    fn challenge_acks_are_rate_limited() {
+        let mut challenge_acks = ChallengeAcks::default();
+        challenge_acks.set_limit(4);
+        let sent = std::iter::from_fn(|| challenge_acks.take().then_some(()))
+            .take(100)
+            .count();
+        // the limit and a random part of up to half of it
+        assert!((4..=6).contains(&sent), "{} challenge ACKs", sent);
+        assert!(!challenge_acks.take());
+        // a lower limit takes effect in the current second
+        challenge_acks.quota = 5;
+        challenge_acks.set_limit(2);
+        assert_eq!(challenge_acks.quota, 2);
+        // the quota is refilled in the next second
+        challenge_acks.quota = 0;
+        challenge_acks.second = challenge_acks
+            .second
+            .map(|second| second - time::Duration::from_secs(1));
+        assert!(challenge_acks.take());
+        challenge_acks.set_limit(0);
+        challenge_acks.second = None;
+        assert!(!challenge_acks.take());
+    }
+
+    #[test]
+    fn receive_window_opens_by_at_least_the_sws_threshold() {
+        let local = (Ipv4Addr::new(192, 168, 0, 2), 80);
+        let remote = (Ipv4Addr::new(192, 168, 0, 1), 40000);
+        let mut c = TCB::connect(local, remote, 0, 10000);
+        c.mss = 1000;
+        // the buffer is full, and the window closed
+        c.incoming.extend([0; 10000]);
+        c.recv.wnd = 0;
+        assert!(!c.open_window());
+        // less than an MSS has been read
+        c.incoming.drain(..900);
+        assert!(!c.open_window());
+        assert_eq!(c.recv.wnd, 0);
+        c.incoming.drain(..100);
+        assert!(c.open_window());
+        assert_eq!(c.recv.wnd, 1000);
+        // with a small buffer, half of it is enough
+        c.set_recv_buffer(1000);
+        c.incoming.clear();
+        c.incoming.extend([0; 1000]);
+        c.recv.wnd = 0;
+        c.incoming.drain(..500);
+        assert!(c.open_window());
+        assert_eq!(c.recv.wnd, 500);
+        // the window never moves back
+        c.incoming.extend([0; 500]);
+        assert!(!c.open_window());
+        assert_eq!(c.recv.wnd, 500);
+    }
+
+    #[test]
+    fn cookie_round_trip() {
+        let iss = IssGenerator::default();
+        let local = (Ipv4Addr::new(192, 168, 0, 2), 80);
+        let remote = (Ipv4Addr::new(192, 168, 0, 1), 40000);
+        for (mss, expected) in [(1460, 1460), (1400, 1220), (100, 536), (9000, 1460)] {
+            let cookie = iss.encode_cookie(local, remote, 7, mss, 255);
+            assert_eq!(
+                iss.decode_cookie(local, remote, 7, cookie, 255),
+                Some(expected)
+            );
+            // the counter wraps around
+            assert_eq!(
+                iss.decode_cookie(local, remote, 7, cookie, 1),
+                Some(expected)
+            );
+        }
+        let cookie = iss.cookie(local, remote, 7, 1460);
+        assert_eq!(iss.check_cookie(local, remote, 7, cookie), Some(1460));
+        // another tuple
+        assert_eq!(iss.check_cookie(local, (remote.0, 40001), 7, cookie), None);
+    }
+
+    #[test]
+    fn cookie_expires() {
+        let iss = IssGenerator::default();
+        let local = (Ipv4Addr::new(192, 168, 0, 2), 80);
+        let remote = (Ipv4Addr::new(192, 168, 0, 1), 40000);
+        let cookie = iss.encode_cookie(local, remote, 7, 1460, 10);
+        assert_eq!(iss.decode_cookie(local, remote, 7, cookie, 12), Some(1460));
+        assert_eq!(iss.decode_cookie(local, remote, 7, cookie, 13), None);
+        // a cookie from the future
+        assert_eq!(iss.decode_cookie(local, remote, 7, cookie, 9), None);
     }
 }
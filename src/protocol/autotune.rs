@@ -0,0 +1,120 @@
+use std::time;
+
+/// the round trip time assumed before one is measured
+const DEFAULT_RTT: time::Duration = time::Duration::from_millis(200);
+/// the buffer goes back to its initial size after the application has read nothing for this long
+const IDLE: time::Duration = time::Duration::from_secs(1);
+
+/// Receive buffer auto-tuning, in the style of the Dynamic Right-Sizing of Linux. The bytes the
+/// application reads in each RTT are measured, and the buffer grows to twice of them, so the
+/// peer is never limited by our window while the application keeps up.
+pub(crate) struct Autotune {
+    /// the largest buffer to grow to, None if the buffer has a fixed size
+    pub max: Option<usize>,
+    // the buffer size to go back to when idle
+    base: usize,
+    // the receiver side RTT, measured from the TSecr of data segments, RFC 7323 4.2
+    rtt: Option<time::Duration>,
+    // the bytes read in the last whole measurement
+    space: usize,
+    // the bytes read in the current measurement
+    copied: usize,
+    // when the current measurement started
+    since: time::Instant,
+    // when the application read the last time
+    last_read: time::Instant,
+}
+
+impl Autotune {
+    pub fn new(base: usize) -> Self {
+        let now = time::Instant::now();
+        Self {
+            max: None,
+            base,
+            rtt: None,
+            space: 0,
+            copied: 0,
+            since: now,
+            last_read: now,
+        }
+    }
+
+    /// the buffer size to go back to when idle.
+    pub fn set_base(&mut self, base: usize) {
+        self.base = base;
+    }
+
+    /// records an RTT sample taken from a data segment.
+    pub fn on_rtt(&mut self, sample: time::Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => rtt * 7 / 8 + sample / 8,
+            None => sample,
+        });
+    }
+
+    /// the application has read `n` bytes, returns the new buffer size once a measurement
+    /// shows that `buffer` is too small. `srtt` is used while there is no receiver side RTT.
+    pub fn on_read(
+        &mut self,
+        n: usize,
+        buffer: usize,
+        srtt: Option<time::Duration>,
+    ) -> Option<usize> {
+        let max = self.max?;
+        let now = time::Instant::now();
+        self.last_read = now;
+        self.copied += n;
+        let rtt = self.rtt.or(srtt).unwrap_or(DEFAULT_RTT);
+        if now.duration_since(self.since) < rtt {
+            return None;
+        }
+        let copied = std::mem::take(&mut self.copied);
+        self.since = now;
+        let grown = copied > self.space;
+        self.space = copied;
+        let target = std::cmp::min(copied.saturating_mul(2), max);
+        (grown && target > buffer).then_some(target)
+    }
+
+    /// returns the initial buffer size once the application has read nothing for a while and
+    /// `buffer` has grown beyond it.
+    pub fn on_idle(&mut self, buffer: usize) -> Option<usize> {
+        self.max?;
+        if buffer <= self.base || self.last_read.elapsed() < IDLE {
+            return None;
+        }
+        self.space = 0;
+        self.copied = 0;
+        self.since = time::Instant::now();
+        Some(self.base)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_to_twice_the_bytes_read_per_rtt() {
+        let mut autotune = Autotune::new(1000);
+        autotune.on_rtt(time::Duration::ZERO);
+        // no growth while auto-tuning is off
+        assert_eq!(autotune.on_read(5000, 1000, None), None);
+        autotune.max = Some(8000);
+        assert_eq!(autotune.on_read(3000, 1000, None), Some(6000));
+        // as many bytes as the last time, the rate did not grow
+        assert_eq!(autotune.on_read(3000, 6000, None), None);
+        // up to the cap
+        assert_eq!(autotune.on_read(5000, 6000, None), Some(8000));
+    }
+
+    #[test]
+    fn idle_buffer_goes_back_to_the_base() {
+        let mut autotune = Autotune::new(1000);
+        autotune.max = Some(8000);
+        assert_eq!(autotune.on_idle(6000), None);
+        autotune.last_read -= IDLE;
+        assert_eq!(autotune.on_idle(1000), None);
+        assert_eq!(autotune.on_idle(6000), Some(1000));
+    }
+}
@@ -0,0 +1,238 @@
+use std::time;
+
+mod bbr;
+mod cubic;
+mod newreno;
+pub use bbr::Bbr;
+pub use cubic::Cubic;
+pub use newreno::NewReno;
+
+/// the sender maximum segment size used before the MSS option is negotiated, RFC 1122 4.2.2.6
+pub const DEFAULT_MSS: usize = 536;
+
+/// What an ACK which acknowledges new data tells the congestion controller.
+#[derive(Debug, Clone)]
+pub struct Ack {
+    /// the acknowledgment number, which is the new SND.UNA
+    pub ackn: u32,
+    /// the number of newly acknowledged bytes
+    pub acked: usize,
+    /// the number of bytes in flight after this ACK
+    pub flight: usize,
+    /// the total number of bytes delivered to the peer so far
+    pub delivered: u64,
+    /// the delivery rate measured by this ACK
+    pub rate: Option<RateSample>,
+    /// when the ACK arrived
+    pub now: time::Instant,
+}
+
+#[cfg(test)]
+impl Ack {
+    /// an ACK without a rate sample, for the tests of the controllers.
+    pub(crate) fn new(ackn: u32, acked: usize, flight: usize) -> Self {
+        Self {
+            ackn,
+            acked,
+            flight,
+            delivered: 0,
+            rate: None,
+            now: time::Instant::now(),
+        }
+    }
+}
+
+/// A delivery rate sample, see draft-cheng-iccrg-delivery-rate-estimation.
+#[derive(Debug, Clone)]
+pub struct RateSample {
+    /// delivered bytes per second
+    pub delivery_rate: f64,
+    /// the number of bytes delivered in the interval
+    pub delivered: u64,
+    /// the total delivered bytes when the acknowledged segment was sent
+    pub prior_delivered: u64,
+    /// the length of the interval
+    pub interval: time::Duration,
+    /// whether the sender had nothing to send when the segment was sent, the sample may
+    /// underestimate the bandwidth then.
+    pub app_limited: bool,
+}
+
+/// The delivery state recorded with a segment when it is (re)transmitted.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TxState {
+    delivered: u64,
+    delivered_time: time::Instant,
+    first_sent_time: time::Instant,
+    app_limited: bool,
+}
+
+/// Delivery rate estimation of the sender, draft-cheng-iccrg-delivery-rate-estimation.
+pub(crate) struct Delivery {
+    // the total number of bytes delivered
+    delivered: u64,
+    // when `delivered` was updated for the last time
+    delivered_time: time::Instant,
+    // the send time of the segment which was acknowledged most recently
+    first_sent_time: time::Instant,
+    // the end of the app-limited phase in delivered bytes, 0 if the sender is not app-limited
+    app_limited: u64,
+    // the state of the most recently sent segment acknowledged by the current ACK, and
+    // the intervals of it
+    sample: Option<(TxState, time::Duration, time::Duration)>,
+}
+
+impl Default for Delivery {
+    fn default() -> Self {
+        let now = time::Instant::now();
+        Self {
+            delivered: 0,
+            delivered_time: now,
+            first_sent_time: now,
+            app_limited: 0,
+            sample: None,
+        }
+    }
+}
+
+impl Delivery {
+    pub(crate) fn delivered(&self) -> u64 {
+        self.delivered
+    }
+
+    /// records the delivery state for a segment which is being sent with `flight` bytes in flight.
+    pub(crate) fn on_send(&mut self, flight: usize, now: time::Instant) -> TxState {
+        if flight == 0 {
+            self.first_sent_time = now;
+            self.delivered_time = now;
+        }
+        TxState {
+            delivered: self.delivered,
+            delivered_time: self.delivered_time,
+            first_sent_time: self.first_sent_time,
+            app_limited: self.app_limited != 0,
+        }
+    }
+
+    /// marks the sender as app-limited when it has nothing to send.
+    pub(crate) fn set_app_limited(&mut self, flight: usize) {
+        self.app_limited = std::cmp::max(self.delivered + flight as u64, 1);
+    }
+
+    /// counts bytes which are acknowledged.
+    pub(crate) fn on_delivered(&mut self, bytes: usize, now: time::Instant) {
+        self.delivered += bytes as u64;
+        self.delivered_time = now;
+        if self.app_limited != 0 && self.delivered > self.app_limited {
+            self.app_limited = 0;
+        }
+    }
+
+    /// called for each segment which is entirely acknowledged, `sent` is the last time it was sent.
+    pub(crate) fn on_acked(&mut self, tx: TxState, sent: time::Instant) {
+        let newer = self
+            .sample
+            .as_ref()
+            .is_none_or(|(prior, _, _)| tx.delivered >= prior.delivered);
+        if newer {
+            let send_elapsed = sent.saturating_duration_since(tx.first_sent_time);
+            let ack_elapsed = self
+                .delivered_time
+                .saturating_duration_since(tx.delivered_time);
+            self.sample = Some((tx, send_elapsed, ack_elapsed));
+            self.first_sent_time = sent;
+        }
+    }
+
+    /// generates the rate sample of the current ACK.
+    pub(crate) fn sample(&mut self) -> Option<RateSample> {
+        let (tx, send_elapsed, ack_elapsed) = self.sample.take()?;
+        let interval = std::cmp::max(send_elapsed, ack_elapsed);
+        let delivered = self.delivered - tx.delivered;
+        if interval.is_zero() || delivered == 0 {
+            return None;
+        }
+        Some(RateSample {
+            delivery_rate: delivered as f64 / interval.as_secs_f64(),
+            delivered,
+            prior_delivered: tx.delivered,
+            interval,
+            app_limited: tx.app_limited,
+        })
+    }
+}
+
+/// Congestion control of the sending side. The TCB asks it how many bytes may be in flight, and
+/// tells it about ACKs, losses, timeouts and round trip time samples.
+pub trait CongestionControl: Send {
+    /// the name of the algorithm
+    fn name(&self) -> &'static str;
+
+    /// the congestion window in bytes
+    fn cwnd(&self) -> usize;
+
+    /// the slow start threshold in bytes
+    fn ssthresh(&self) -> usize;
+
+    /// a human readable description of the internal state, which is shown in the stats
+    fn describe(&self) -> String {
+        String::new()
+    }
+
+    /// whether the sender is recovering from a loss which was detected by duplicate ACKs
+    fn in_recovery(&self) -> bool {
+        false
+    }
+
+    /// called when an ACK acknowledges new data.
+    fn on_ack(&mut self, ack: &Ack);
+
+    /// called for every duplicate ACK.
+    fn on_dup_ack(&mut self) {}
+
+    /// called when a loss is detected by duplicate ACKs. `high` is SND.NXT at that time and
+    /// `flight` is the number of bytes in flight.
+    fn on_loss(&mut self, high: u32, flight: usize);
+
+    /// called when the retransmission timer expires.
+    fn on_timeout(&mut self, flight: usize);
+
+    /// called with every valid round trip time sample.
+    fn on_rtt(&mut self, _rtt: time::Duration) {}
+
+    /// the rate in bytes per second at which segments should be paced, None if they can be
+    /// sent as soon as the window allows.
+    fn pacing_rate(&self) -> Option<f64> {
+        None
+    }
+}
+
+/// The congestion control algorithms which can be chosen for a listener or a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Algorithm {
+    #[default]
+    NewReno,
+    Cubic,
+    Bbr,
+}
+
+impl Algorithm {
+    pub fn build(self, mss: usize) -> Box<dyn CongestionControl> {
+        match self {
+            Algorithm::NewReno => Box::new(NewReno::new(mss)),
+            Algorithm::Cubic => Box::new(Cubic::new(mss)),
+            Algorithm::Bbr => Box::new(Bbr::new(mss)),
+        }
+    }
+}
+
+/// the initial congestion window, RFC 5681 3.1
+pub(crate) fn initial_window(mss: usize) -> usize {
+    if mss > 2190 {
+        2 * mss
+    } else if mss > 1095 {
+        3 * mss
+    } else {
+        4 * mss
+    }
+}
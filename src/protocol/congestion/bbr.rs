@@ -0,0 +1,423 @@
+use super::{initial_window, Ack, CongestionControl};
+use crate::util;
+use std::collections::VecDeque;
+use std::time;
+
+/// the pacing gain of Startup, 2/ln(2), which doubles the sending rate every round trip
+const HIGH_GAIN: f64 = 2.885;
+/// the pacing gains of ProbeBW, one phase lasts about one min_rtt
+const PACING_GAIN_CYCLE: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+/// the cwnd gain out of Startup and Drain
+const CWND_GAIN: f64 = 2.0;
+/// the length of the max bandwidth filter in round trips
+const BW_FILTER_ROUNDS: u64 = 10;
+/// how long a min_rtt measurement is valid
+const MIN_RTT_WINDOW: time::Duration = time::Duration::from_secs(10);
+/// how long ProbeRTT keeps the window small
+const PROBE_RTT_DURATION: time::Duration = time::Duration::from_millis(200);
+/// the pipe is full if the bandwidth grows less than this in FULL_BW_ROUNDS round trips
+const FULL_BW_THRESH: f64 = 1.25;
+const FULL_BW_ROUNDS: u32 = 3;
+/// the minimum cwnd in segments
+const MIN_CWND: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Startup,
+    Drain,
+    ProbeBw,
+    ProbeRtt,
+}
+
+/// A BBRv1-like controller, see draft-cardwell-iccrg-bbr-congestion-control-00. The bottleneck
+/// bandwidth and the round trip propagation time are estimated from delivery rate samples and
+/// round trip time samples. The sending rate is paced at the estimated bandwidth, and a loss is
+/// not taken as a signal of congestion.
+pub struct Bbr {
+    mss: usize,
+    mode: Mode,
+    cwnd: usize,
+    // the cwnd saved when entering ProbeRTT or recovery, restored afterwards
+    prior_cwnd: usize,
+    pacing_gain: f64,
+    cwnd_gain: f64,
+
+    // windowed max of the delivery rate, (round, bytes per second), decreasing in rate
+    bw_filter: VecDeque<(u64, f64)>,
+    min_rtt: Option<time::Duration>,
+    min_rtt_stamp: time::Instant,
+    // whether min_rtt was refreshed because it had expired
+    min_rtt_expired: bool,
+
+    // round trip counting with delivered bytes
+    round: u64,
+    next_round_delivered: u64,
+    round_start: bool,
+
+    // full pipe detection of Startup
+    full_bw: f64,
+    full_bw_count: u32,
+    filled_pipe: bool,
+
+    // the current phase of the ProbeBW gain cycle
+    cycle_index: usize,
+    cycle_stamp: time::Instant,
+
+    // when ProbeRTT may end, None before the inflight drops to the minimum window
+    probe_rtt_done: Option<time::Instant>,
+    probe_rtt_round_done: bool,
+
+    // the highest sequence number sent when the loss was detected
+    recover: Option<u32>,
+    // whether the first round of recovery, in which packet conservation applies, is over
+    conservation_round: Option<u64>,
+}
+
+impl Bbr {
+    pub fn new(mss: usize) -> Self {
+        let now = time::Instant::now();
+        Self {
+            mss,
+            mode: Mode::Startup,
+            cwnd: initial_window(mss),
+            prior_cwnd: 0,
+            pacing_gain: HIGH_GAIN,
+            cwnd_gain: HIGH_GAIN,
+            bw_filter: VecDeque::new(),
+            min_rtt: None,
+            min_rtt_stamp: now,
+            min_rtt_expired: false,
+            round: 0,
+            next_round_delivered: 0,
+            round_start: false,
+            full_bw: 0.0,
+            full_bw_count: 0,
+            filled_pipe: false,
+            cycle_index: 0,
+            cycle_stamp: now,
+            probe_rtt_done: None,
+            probe_rtt_round_done: false,
+            recover: None,
+            conservation_round: None,
+        }
+    }
+
+    /// the estimated bottleneck bandwidth in bytes per second
+    fn bw(&self) -> f64 {
+        self.bw_filter.front().map_or(0.0, |&(_, bw)| bw)
+    }
+
+    /// the estimated bandwidth-delay product scaled by `gain`, None before the model has any samples
+    fn bdp(&self, gain: f64) -> Option<usize> {
+        let rtt = self.min_rtt?;
+        if self.bw() == 0.0 {
+            return None;
+        }
+        Some((gain * self.bw() * rtt.as_secs_f64()) as usize)
+    }
+
+    fn min_cwnd(&self) -> usize {
+        MIN_CWND * self.mss
+    }
+
+    fn update_round(&mut self, ack: &Ack) {
+        self.round_start = false;
+        if let Some(rate) = &ack.rate {
+            if rate.prior_delivered >= self.next_round_delivered {
+                self.next_round_delivered = ack.delivered;
+                self.round += 1;
+                self.round_start = true;
+            }
+        }
+    }
+
+    fn update_bw(&mut self, ack: &Ack) {
+        let rate = match &ack.rate {
+            Some(rate) => rate,
+            None => return,
+        };
+        // an app-limited sample only tells a lower bound of the bandwidth.
+        if rate.app_limited && rate.delivery_rate < self.bw() {
+            return;
+        }
+        while self
+            .bw_filter
+            .back()
+            .is_some_and(|&(_, bw)| bw <= rate.delivery_rate)
+        {
+            self.bw_filter.pop_back();
+        }
+        self.bw_filter.push_back((self.round, rate.delivery_rate));
+        while self
+            .bw_filter
+            .front()
+            .is_some_and(|&(round, _)| round + BW_FILTER_ROUNDS <= self.round)
+        {
+            self.bw_filter.pop_front();
+        }
+    }
+
+    fn check_full_pipe(&mut self, ack: &Ack) {
+        if self.filled_pipe || !self.round_start || ack.rate.as_ref().is_none_or(|r| r.app_limited)
+        {
+            return;
+        }
+        if self.bw() >= self.full_bw * FULL_BW_THRESH {
+            self.full_bw = self.bw();
+            self.full_bw_count = 0;
+            return;
+        }
+        self.full_bw_count += 1;
+        self.filled_pipe = self.full_bw_count >= FULL_BW_ROUNDS;
+    }
+
+    fn enter_probe_bw(&mut self, now: time::Instant) {
+        self.mode = Mode::ProbeBw;
+        self.cwnd_gain = CWND_GAIN;
+        // start at any phase but the draining one, so flows do not probe in lockstep.
+        let seed = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        let i = seed as usize % (PACING_GAIN_CYCLE.len() - 1);
+        self.cycle_index = if i == 0 { 0 } else { i + 1 };
+        self.pacing_gain = PACING_GAIN_CYCLE[self.cycle_index];
+        self.cycle_stamp = now;
+    }
+
+    fn update_gain_cycle(&mut self, ack: &Ack) {
+        let rtt = self.min_rtt.unwrap_or_default();
+        let mut next = ack.now.duration_since(self.cycle_stamp) > rtt;
+        if self.pacing_gain > 1.0 {
+            // probing goes on until the inflight reaches the probed target or a loss happens.
+            next &= self.recover.is_some()
+                || self
+                    .bdp(self.pacing_gain)
+                    .is_none_or(|bdp| ack.flight >= bdp);
+        } else if self.pacing_gain < 1.0 {
+            // draining ends as soon as the queue is gone.
+            next |= self.bdp(1.0).is_some_and(|bdp| ack.flight <= bdp);
+        }
+        if next {
+            self.cycle_index = (self.cycle_index + 1) % PACING_GAIN_CYCLE.len();
+            self.pacing_gain = PACING_GAIN_CYCLE[self.cycle_index];
+            self.cycle_stamp = ack.now;
+        }
+    }
+
+    fn update_mode(&mut self, ack: &Ack) {
+        if self.mode == Mode::Startup && self.filled_pipe {
+            self.mode = Mode::Drain;
+            self.pacing_gain = 1.0 / HIGH_GAIN;
+            self.cwnd_gain = HIGH_GAIN;
+        }
+        if self.mode == Mode::Drain && self.bdp(1.0).is_none_or(|bdp| ack.flight <= bdp) {
+            self.enter_probe_bw(ack.now);
+        }
+        if self.mode == Mode::ProbeBw {
+            self.update_gain_cycle(ack);
+        }
+
+        let expired =
+            self.min_rtt_expired || ack.now.duration_since(self.min_rtt_stamp) > MIN_RTT_WINDOW;
+        self.min_rtt_expired = false;
+        if expired && self.mode != Mode::ProbeRtt {
+            self.mode = Mode::ProbeRtt;
+            self.pacing_gain = 1.0;
+            self.cwnd_gain = 1.0;
+            self.prior_cwnd = std::cmp::max(self.prior_cwnd, self.cwnd);
+            self.probe_rtt_done = None;
+        }
+        if self.mode == Mode::ProbeRtt {
+            self.handle_probe_rtt(ack);
+        }
+    }
+
+    fn handle_probe_rtt(&mut self, ack: &Ack) {
+        match self.probe_rtt_done {
+            None if ack.flight <= self.min_cwnd() => {
+                self.probe_rtt_done = Some(ack.now + PROBE_RTT_DURATION);
+                self.probe_rtt_round_done = false;
+                self.next_round_delivered = ack.delivered;
+            }
+            None => {}
+            Some(done) => {
+                self.probe_rtt_round_done |= self.round_start;
+                if self.probe_rtt_round_done && ack.now >= done {
+                    self.min_rtt_stamp = ack.now;
+                    self.cwnd = std::cmp::max(self.cwnd, self.prior_cwnd);
+                    self.prior_cwnd = 0;
+                    if self.filled_pipe {
+                        self.enter_probe_bw(ack.now);
+                    } else {
+                        self.mode = Mode::Startup;
+                        self.pacing_gain = HIGH_GAIN;
+                        self.cwnd_gain = HIGH_GAIN;
+                    }
+                }
+            }
+        }
+    }
+
+    fn set_cwnd(&mut self, ack: &Ack) {
+        let target = self
+            .bdp(self.cwnd_gain)
+            .map_or(initial_window(self.mss), |bdp| bdp + 3 * self.mss);
+
+        // packet conservation in the first round of recovery: only as much as was delivered
+        // is sent.
+        if self
+            .conservation_round
+            .is_some_and(|round| round >= self.round)
+        {
+            self.cwnd = std::cmp::max(self.cwnd, ack.flight + ack.acked);
+        } else if self.filled_pipe {
+            self.cwnd = std::cmp::min(self.cwnd + ack.acked, target);
+        } else if self.cwnd < target || ack.delivered < initial_window(self.mss) as u64 {
+            self.cwnd += ack.acked;
+        }
+        self.cwnd = std::cmp::max(self.cwnd, self.min_cwnd());
+        if self.mode == Mode::ProbeRtt {
+            self.cwnd = std::cmp::min(self.cwnd, self.min_cwnd());
+        }
+    }
+}
+
+impl CongestionControl for Bbr {
+    fn name(&self) -> &'static str {
+        "bbr"
+    }
+
+    fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    fn ssthresh(&self) -> usize {
+        usize::MAX
+    }
+
+    fn in_recovery(&self) -> bool {
+        self.recover.is_some()
+    }
+
+    fn describe(&self) -> String {
+        let mode = match self.mode {
+            Mode::Startup => "startup",
+            Mode::Drain => "drain",
+            Mode::ProbeBw => "probe bw",
+            Mode::ProbeRtt => "probe rtt",
+        };
+        format!(
+            "{}, bw: {:.0}B/s, min_rtt: {:?}, pacing_gain: {:.2}, cwnd_gain: {:.2}",
+            mode,
+            self.bw(),
+            self.min_rtt,
+            self.pacing_gain,
+            self.cwnd_gain
+        )
+    }
+
+    fn on_ack(&mut self, ack: &Ack) {
+        if let Some(recover) = self.recover {
+            if util::le(recover, ack.ackn) {
+                // the recovery is over, the window before the loss is restored.
+                self.recover = None;
+                self.conservation_round = None;
+                self.cwnd = std::cmp::max(self.cwnd, self.prior_cwnd);
+                self.prior_cwnd = 0;
+            }
+        }
+        self.update_round(ack);
+        self.update_bw(ack);
+        self.check_full_pipe(ack);
+        self.update_mode(ack);
+        self.set_cwnd(ack);
+    }
+
+    fn on_loss(&mut self, high: u32, flight: usize) {
+        if self.recover.is_some() {
+            return;
+        }
+        // the model is kept, only the inflight is held for one round trip.
+        self.prior_cwnd = self.cwnd;
+        self.cwnd = std::cmp::max(flight + self.mss, self.min_cwnd());
+        self.recover = Some(high);
+        self.conservation_round = Some(self.round + 1);
+    }
+
+    fn on_timeout(&mut self, _flight: usize) {
+        self.cwnd = self.mss;
+        self.recover = None;
+        self.conservation_round = None;
+    }
+
+    fn on_rtt(&mut self, rtt: time::Duration) {
+        let now = time::Instant::now();
+        let expired = now.duration_since(self.min_rtt_stamp) > MIN_RTT_WINDOW;
+        if expired || self.min_rtt.is_none_or(|min| rtt <= min) {
+            self.min_rtt_expired |= expired;
+            self.min_rtt = Some(rtt);
+            self.min_rtt_stamp = now;
+        }
+    }
+
+    fn pacing_rate(&self) -> Option<f64> {
+        if self.bw() > 0.0 {
+            return Some(self.pacing_gain * self.bw());
+        }
+        let rtt = self.min_rtt?.as_secs_f64();
+        (rtt > 0.0).then(|| self.pacing_gain * initial_window(self.mss) as f64 / rtt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::RateSample;
+    use super::*;
+
+    const MSS: usize = 1000;
+
+    /// an ACK which ends round `round`, with a delivery rate sample of `rate` bytes per second.
+    fn ack(round: u64, flight: usize, rate: f64) -> Ack {
+        let mut ack = Ack::new(0, MSS, flight);
+        ack.delivered = round * MSS as u64;
+        ack.rate = Some(RateSample {
+            delivery_rate: rate,
+            delivered: MSS as u64,
+            prior_delivered: (round - 1) * MSS as u64,
+            interval: time::Duration::from_millis(1),
+            app_limited: false,
+        });
+        ack
+    }
+
+    #[test]
+    fn startup_drain_probe_bw() {
+        let mut cc = Bbr::new(MSS);
+        cc.on_rtt(time::Duration::from_millis(10));
+        // the bandwidth keeps growing, the pipe is not full
+        for round in 1..=4 {
+            cc.on_ack(&ack(round, 50 * MSS, 1e6 * round as f64));
+            assert_eq!(cc.mode, Mode::Startup);
+        }
+        assert_eq!(cc.full_bw_count, 0);
+        // three rounds without 25% more bandwidth fill the pipe, and the queue of Startup is
+        // drained while the inflight is above the BDP of 40000 bytes
+        for round in 5..=7 {
+            cc.on_ack(&ack(round, 50 * MSS, 4e6));
+        }
+        assert!(cc.filled_pipe);
+        assert_eq!(cc.mode, Mode::Drain);
+        assert_eq!(cc.pacing_gain, 1.0 / HIGH_GAIN);
+        assert_eq!(cc.pacing_rate(), Some(4e6 / HIGH_GAIN));
+        cc.on_ack(&ack(8, 50 * MSS, 4e6));
+        assert_eq!(cc.mode, Mode::Drain);
+        cc.on_ack(&ack(9, 30 * MSS, 4e6));
+        assert_eq!(cc.mode, Mode::ProbeBw);
+        assert_eq!(cc.cwnd_gain, CWND_GAIN);
+        // ProbeBW never starts in the draining phase
+        assert_ne!(cc.pacing_gain, 0.75);
+        // cwnd is bounded by 2 * BDP + 3 * MSS
+        assert!(cc.cwnd() <= 83 * MSS);
+    }
+}
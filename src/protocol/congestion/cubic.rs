@@ -0,0 +1,250 @@
+use super::{initial_window, Ack, CongestionControl};
+use crate::util;
+use std::time;
+
+/// the scaling constant of the cubic function
+const C: f64 = 0.4;
+/// the multiplicative window decrease factor
+const BETA: f64 = 0.7;
+
+/// CUBIC, RFC 8312. The windows of the cubic function are counted in segments, and cwnd is kept
+/// as a float so the small increments of congestion avoidance are not lost.
+pub struct Cubic {
+    mss: usize,
+    cwnd: f64,
+    ssthresh: usize,
+    // the window before the last reduction, in segments
+    w_max: f64,
+    // W_max before fast convergence, in segments
+    w_last_max: f64,
+    // the time it takes to grow the window back to W_max, in seconds
+    k: f64,
+    // the start of the current congestion avoidance epoch
+    epoch: Option<time::Instant>,
+    // the minimum round trip time which has been observed
+    min_rtt: Option<time::Duration>,
+    // whether the window grows in the TCP-friendly region
+    tcp_friendly: bool,
+    // the highest sequence number sent when fast recovery was entered
+    recover: Option<u32>,
+    // the retransmission timer has expired and SND.UNA has not moved since
+    timed_out: bool,
+}
+
+impl Cubic {
+    pub fn new(mss: usize) -> Self {
+        Self {
+            mss,
+            cwnd: initial_window(mss) as f64,
+            ssthresh: usize::MAX,
+            w_max: 0.0,
+            w_last_max: 0.0,
+            k: 0.0,
+            epoch: None,
+            min_rtt: None,
+            tcp_friendly: false,
+            recover: None,
+            timed_out: false,
+        }
+    }
+
+    /// W_cubic(t) = C * (t - K)^3 + W_max, RFC 8312 4.1
+    fn w_cubic(&self, t: f64) -> f64 {
+        C * (t - self.k).powi(3) + self.w_max
+    }
+
+    /// reduces W_max with fast convergence when a congestion event happens, RFC 8312 4.5 and 4.6
+    fn reduce(&mut self) {
+        let cwnd = self.cwnd / self.mss as f64;
+        self.w_max = if cwnd < self.w_last_max {
+            cwnd * (1.0 + BETA) / 2.0
+        } else {
+            cwnd
+        };
+        self.w_last_max = cwnd;
+        self.ssthresh = std::cmp::max((self.cwnd * BETA) as usize, 2 * self.mss);
+        self.epoch = None;
+    }
+
+    /// congestion avoidance, RFC 8312 4.2 - 4.4
+    fn avoid(&mut self, ack: &Ack) {
+        let mss = self.mss as f64;
+        let cwnd = self.cwnd / mss;
+        let epoch = match self.epoch {
+            Some(epoch) => epoch,
+            None => {
+                // a new epoch starts, the window will grow back to W_max after K seconds.
+                if cwnd < self.w_max {
+                    self.k = ((self.w_max - cwnd) / C).cbrt();
+                } else {
+                    self.k = 0.0;
+                    self.w_max = cwnd;
+                }
+                self.epoch = Some(ack.now);
+                ack.now
+            }
+        };
+        let rtt = self.min_rtt.unwrap_or_default().as_secs_f64();
+        let t = ack.now.duration_since(epoch).as_secs_f64();
+
+        // the window of the standard TCP in the same time, RFC 8312 4.2
+        let w_est = if rtt > 0.0 {
+            self.w_max * BETA + 3.0 * (1.0 - BETA) / (1.0 + BETA) * t / rtt
+        } else {
+            0.0
+        };
+        let mut target = self.w_cubic(t + rtt);
+        self.tcp_friendly = target < w_est;
+        if self.tcp_friendly {
+            target = w_est;
+        }
+        // the window grows at most by half per round trip.
+        let target = target.min(1.5 * cwnd);
+        if target > cwnd {
+            let acked = ack.acked as f64 / mss;
+            self.cwnd += (target - cwnd) / cwnd * acked * mss;
+        }
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn name(&self) -> &'static str {
+        "cubic"
+    }
+
+    fn cwnd(&self) -> usize {
+        self.cwnd as usize
+    }
+
+    fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+
+    fn in_recovery(&self) -> bool {
+        self.recover.is_some()
+    }
+
+    fn describe(&self) -> String {
+        let phase = if self.recover.is_some() {
+            "fast recovery"
+        } else if self.cwnd() < self.ssthresh {
+            "slow start"
+        } else if self.tcp_friendly {
+            "tcp friendly"
+        } else if self.cwnd / (self.mss as f64) < self.w_max {
+            "concave"
+        } else {
+            "convex"
+        };
+        format!("{}, w_max: {:.1}, k: {:.3}s", phase, self.w_max, self.k)
+    }
+
+    fn on_ack(&mut self, ack: &Ack) {
+        self.timed_out = false;
+        if let Some(recover) = self.recover {
+            if util::le(recover, ack.ackn) {
+                // full acknowledgment, the window is deflated to ssthresh.
+                self.cwnd = self.ssthresh as f64;
+                self.recover = None;
+            } else {
+                // partial acknowledgment, RFC 6582 3.2 (3)
+                self.cwnd = (self.cwnd - ack.acked as f64).max(self.mss as f64);
+                if ack.acked >= self.mss {
+                    self.cwnd += self.mss as f64;
+                }
+            }
+            return;
+        }
+
+        if self.cwnd() < self.ssthresh {
+            self.cwnd += std::cmp::min(ack.acked, self.mss) as f64;
+        } else {
+            self.avoid(ack);
+        }
+    }
+
+    fn on_dup_ack(&mut self) {
+        if self.recover.is_some() {
+            self.cwnd += self.mss as f64;
+        }
+    }
+
+    fn on_loss(&mut self, high: u32, _flight: usize) {
+        if self.recover.is_some() {
+            return;
+        }
+        self.reduce();
+        self.cwnd = (self.ssthresh + 3 * self.mss) as f64;
+        self.recover = Some(high);
+    }
+
+    fn on_timeout(&mut self, _flight: usize) {
+        // RFC 8312 4.7. The later timeouts of the same loss only back off the timer, as Linux
+        // does, otherwise W_max and ssthresh would shrink with every retransmission.
+        if !self.timed_out {
+            self.reduce();
+            self.timed_out = true;
+        }
+        self.cwnd = self.mss as f64;
+        self.recover = None;
+    }
+
+    fn on_rtt(&mut self, rtt: time::Duration) {
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| std::cmp::min(min, rtt)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: usize = 1000;
+
+    fn cubic(segments: f64) -> Cubic {
+        let mut cc = Cubic::new(MSS);
+        cc.cwnd = segments * MSS as f64;
+        cc
+    }
+
+    #[test]
+    fn loss_sets_w_max_and_k() {
+        let mut cc = cubic(100.0);
+        cc.on_loss(100000, 100 * MSS);
+        assert_eq!(cc.w_max, 100.0);
+        assert_eq!(cc.ssthresh(), 70 * MSS);
+        assert_eq!(cc.cwnd(), 73 * MSS);
+        cc.on_ack(&Ack::new(100000, MSS, 70 * MSS));
+        assert_eq!(cc.cwnd(), 70 * MSS);
+        // the first ACK of congestion avoidance starts the epoch, K = cbrt((W_max - cwnd) / C)
+        cc.on_ack(&Ack::new(101000, MSS, 70 * MSS));
+        assert!((cc.k - 75f64.cbrt()).abs() < 1e-9);
+        assert_eq!(cc.w_cubic(cc.k), cc.w_max);
+    }
+
+    #[test]
+    fn fast_convergence() {
+        let mut cc = cubic(100.0);
+        cc.on_timeout(100 * MSS);
+        assert_eq!((cc.w_max, cc.w_last_max), (100.0, 100.0));
+        // the window is lost again below the last W_max, which is reduced further
+        cc.on_ack(&Ack::new(1000, MSS, 0));
+        cc.cwnd = 80.0 * MSS as f64;
+        cc.on_timeout(80 * MSS);
+        assert_eq!(cc.w_max, 80.0 * (1.0 + BETA) / 2.0);
+        assert_eq!(cc.w_last_max, 80.0);
+    }
+
+    #[test]
+    fn repeated_timeouts_reduce_once() {
+        let mut cc = cubic(100.0);
+        cc.on_timeout(100 * MSS);
+        assert_eq!((cc.ssthresh(), cc.cwnd()), (70 * MSS, MSS));
+        // no new ACK between the timeouts
+        cc.on_timeout(100 * MSS);
+        assert_eq!((cc.ssthresh(), cc.w_max), (70 * MSS, 100.0));
+        // a timeout after SND.UNA has moved is another congestion event
+        cc.on_ack(&Ack::new(1000, MSS, 0));
+        cc.on_timeout(100 * MSS);
+        assert_eq!(cc.ssthresh(), 2 * MSS);
+    }
+}
@@ -0,0 +1,169 @@
+use super::{initial_window, Ack, CongestionControl};
+use crate::util;
+
+/// NewReno, RFC 5681 and RFC 6582.
+pub struct NewReno {
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    // bytes acknowledged since cwnd grew last time in congestion avoidance
+    acked: usize,
+    // the highest sequence number sent when fast recovery was entered
+    recover: Option<u32>,
+}
+
+impl NewReno {
+    pub fn new(mss: usize) -> Self {
+        Self {
+            mss,
+            cwnd: initial_window(mss),
+            ssthresh: usize::MAX,
+            acked: 0,
+            recover: None,
+        }
+    }
+}
+
+impl CongestionControl for NewReno {
+    fn name(&self) -> &'static str {
+        "newreno"
+    }
+
+    fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+
+    fn in_recovery(&self) -> bool {
+        self.recover.is_some()
+    }
+
+    fn describe(&self) -> String {
+        if self.recover.is_some() {
+            "fast recovery"
+        } else if self.cwnd < self.ssthresh {
+            "slow start"
+        } else {
+            "congestion avoidance"
+        }
+        .to_string()
+    }
+
+    fn on_ack(&mut self, ack: &Ack) {
+        if let Some(recover) = self.recover {
+            if util::le(recover, ack.ackn) {
+                // full acknowledgment, RFC 6582 3.2 (3)
+                self.cwnd = std::cmp::min(
+                    self.ssthresh,
+                    std::cmp::max(ack.flight, self.mss) + self.mss,
+                );
+                self.recover = None;
+            } else {
+                // partial acknowledgment, RFC 6582 3.2 (3): deflate the window by the amount of
+                // new data acknowledged, then add back one segment.
+                self.cwnd = self.cwnd.saturating_sub(ack.acked);
+                if ack.acked >= self.mss {
+                    self.cwnd += self.mss;
+                }
+            }
+            return;
+        }
+
+        if self.cwnd < self.ssthresh {
+            // slow start, RFC 5681 (2)
+            self.cwnd += std::cmp::min(ack.acked, self.mss);
+        } else {
+            // congestion avoidance with appropriate byte counting, RFC 5681 (3)
+            self.acked += ack.acked;
+            if self.acked >= self.cwnd {
+                self.acked -= self.cwnd;
+                self.cwnd += self.mss;
+            }
+        }
+    }
+
+    fn on_dup_ack(&mut self) {
+        // inflate the window for the segment which has left the network, RFC 6582 3.2 (4)
+        if self.recover.is_some() {
+            self.cwnd += self.mss;
+        }
+    }
+
+    fn on_loss(&mut self, high: u32, flight: usize) {
+        if self.recover.is_some() {
+            return;
+        }
+        // RFC 5681 (4) and RFC 6582 3.2 (2)
+        self.ssthresh = std::cmp::max(flight / 2, 2 * self.mss);
+        self.cwnd = self.ssthresh + 3 * self.mss;
+        self.acked = 0;
+        self.recover = Some(high);
+    }
+
+    fn on_timeout(&mut self, flight: usize) {
+        // RFC 5681 (4), the loss window is one segment.
+        self.ssthresh = std::cmp::max(flight / 2, 2 * self.mss);
+        self.cwnd = self.mss;
+        self.acked = 0;
+        self.recover = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: usize = 1000;
+
+    #[test]
+    fn slow_start_then_congestion_avoidance() {
+        let mut cc = NewReno::new(MSS);
+        assert_eq!(cc.cwnd(), 4 * MSS);
+        // one MSS for each ACK, however much it acknowledges
+        cc.on_ack(&Ack::new(2000, 2 * MSS, 0));
+        assert_eq!(cc.cwnd(), 5 * MSS);
+        cc.on_ack(&Ack::new(2500, 500, 0));
+        assert_eq!(cc.cwnd(), 5 * MSS + 500);
+        // after a timeout, cwnd grows by one MSS per cwnd of acknowledged data above ssthresh
+        cc.on_timeout(8 * MSS);
+        assert_eq!((cc.cwnd(), cc.ssthresh()), (MSS, 4 * MSS));
+        for ackn in 1..=3 {
+            cc.on_ack(&Ack::new(ackn * 1000, MSS, 0));
+        }
+        assert_eq!(cc.cwnd(), 4 * MSS);
+        assert_eq!(cc.describe(), "congestion avoidance");
+        for ackn in 4..=6 {
+            cc.on_ack(&Ack::new(ackn * 1000, MSS, 0));
+        }
+        assert_eq!(cc.cwnd(), 4 * MSS);
+        cc.on_ack(&Ack::new(7000, MSS, 0));
+        assert_eq!(cc.cwnd(), 5 * MSS);
+    }
+
+    #[test]
+    fn fast_recovery_with_partial_and_full_acks() {
+        let mut cc = NewReno::new(MSS);
+        cc.on_loss(10000, 10 * MSS);
+        assert!(cc.in_recovery());
+        assert_eq!((cc.cwnd(), cc.ssthresh()), (8 * MSS, 5 * MSS));
+        // a second loss in the same window does not reduce it again
+        cc.on_loss(10000, 8 * MSS);
+        assert_eq!(cc.ssthresh(), 5 * MSS);
+        cc.on_dup_ack();
+        assert_eq!(cc.cwnd(), 9 * MSS);
+        // a partial ACK deflates the window by the acknowledged data and adds one MSS
+        cc.on_ack(&Ack::new(3000, 3 * MSS, 7 * MSS));
+        assert!(cc.in_recovery());
+        assert_eq!(cc.cwnd(), 7 * MSS);
+        // the full ACK leaves fast recovery with at most ssthresh
+        cc.on_ack(&Ack::new(10000, 7 * MSS, 0));
+        assert!(!cc.in_recovery());
+        assert_eq!(cc.cwnd(), 2 * MSS);
+        cc.on_loss(20000, 10 * MSS);
+        cc.on_ack(&Ack::new(20000, 10 * MSS, 9 * MSS));
+        assert_eq!(cc.cwnd(), 5 * MSS);
+    }
+}
@@ -0,0 +1,203 @@
+use crate::util;
+use etherparse::{TcpOptionElement, TcpOptionsIterator};
+use std::sync::OnceLock;
+use std::time;
+
+/// TS.Recent is invalid after this long without an update, RFC 7323 5.5
+const TS_RECENT_LIFETIME: time::Duration = time::Duration::from_secs(24 * 24 * 60 * 60);
+
+/// The options of a received segment which we understand, the others are ignored.
+#[derive(Debug, Default)]
+pub(crate) struct Options {
+    /// the peer accepts SACK options, only meaningful on a SYN
+    pub sack_permitted: bool,
+    /// the SACK blocks, [left edge, right edge)
+    pub sack: Vec<(u32, u32)>,
+    /// the window scale of the peer, only meaningful on a SYN
+    pub window_scale: Option<u8>,
+    /// the MSS of the peer, only meaningful on a SYN
+    pub mss: Option<u16>,
+    /// TSval and TSecr
+    pub timestamp: Option<(u32, u32)>,
+}
+
+impl Options {
+    /// collects the options until the end of the list or the first malformed option.
+    pub fn parse(iter: TcpOptionsIterator) -> Self {
+        let mut options = Self::default();
+        for option in iter.map_while(Result::ok) {
+            match option {
+                TcpOptionElement::SelectiveAcknowledgementPermitted => {
+                    options.sack_permitted = true;
+                }
+                TcpOptionElement::SelectiveAcknowledgement(first, rest) => {
+                    options.sack.push(first);
+                    options.sack.extend(rest.iter().flatten());
+                }
+                TcpOptionElement::MaximumSegmentSize(mss) => {
+                    options.mss = Some(mss);
+                }
+                TcpOptionElement::WindowScale(shift) => {
+                    options.window_scale = Some(shift);
+                }
+                TcpOptionElement::Timestamp(val, ecr) => {
+                    options.timestamp = Some((val, ecr));
+                }
+                _ => {}
+            }
+        }
+        options
+    }
+}
+
+/// the length of the options in bytes, which must be at most 40.
+pub(crate) fn len(options: &[TcpOptionElement]) -> usize {
+    options
+        .iter()
+        .map(|option| match option {
+            TcpOptionElement::Nop => 1,
+            TcpOptionElement::MaximumSegmentSize(_) => 4,
+            TcpOptionElement::WindowScale(_) => 3,
+            TcpOptionElement::SelectiveAcknowledgementPermitted => 2,
+            TcpOptionElement::SelectiveAcknowledgement(_, rest) => {
+                10 + 8 * rest.iter().flatten().count()
+            }
+            TcpOptionElement::Timestamp(_, _) => 10,
+        })
+        .sum()
+}
+
+/// The timestamps option, RFC 7323 sections 3 - 5.
+pub(crate) struct Timestamps {
+    /// whether both sides send timestamps. It is offered until the SYN of the peer arrives
+    /// without the option.
+    pub enabled: bool,
+    // the latest TSval of the peer which should be echoed
+    recent: u32,
+    // when `recent` was updated, None before the first TSval arrives
+    recent_age: Option<time::Instant>,
+    // the acknowledgment number of the latest segment we sent
+    last_ack_sent: u32,
+}
+
+impl Default for Timestamps {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            recent: 0,
+            recent_age: None,
+            last_ack_sent: 0,
+        }
+    }
+}
+
+impl Timestamps {
+    /// our timestamp clock, which ticks in milliseconds. It is shared by all the connections, so
+    /// the timestamps of a new incarnation of a connection are larger than the old ones. 0 is
+    /// never used, as a TSecr of 0 tells nothing.
+    pub fn now() -> u32 {
+        static EPOCH: OnceLock<time::Instant> = OnceLock::new();
+        let ms = EPOCH.get_or_init(time::Instant::now).elapsed().as_millis() as u32;
+        ms.wrapping_add(1).max(1)
+    }
+
+    /// the option of a segment which acknowledges `ackn`.
+    pub fn option(&mut self, ackn: u32) -> TcpOptionElement {
+        self.last_ack_sent = ackn;
+        TcpOptionElement::Timestamp(Self::now(), self.recent)
+    }
+
+    /// negotiates with the option on the SYN of the peer.
+    pub fn on_syn(&mut self, timestamp: Option<(u32, u32)>) {
+        match timestamp {
+            Some((val, _)) if self.enabled => {
+                self.recent = val;
+                self.recent_age = Some(time::Instant::now());
+            }
+            _ => self.enabled = false,
+        }
+    }
+
+    /// PAWS, RFC 7323 5.3 R1: a segment whose TSval is older than TS.Recent is an old duplicate.
+    pub fn is_old(&mut self, timestamp: Option<(u32, u32)>) -> bool {
+        let (val, _) = match timestamp {
+            Some(timestamp) if self.enabled => timestamp,
+            _ => return false,
+        };
+        match self.recent_age {
+            Some(age) if age.elapsed() > TS_RECENT_LIFETIME => {
+                self.recent_age = None;
+                false
+            }
+            Some(_) => util::lt(val, self.recent),
+            None => false,
+        }
+    }
+
+    /// takes the TSval of an acceptable segment starting at `seqn` as TS.Recent, if the segment
+    /// covers the last acknowledgment we sent, RFC 7323 4.3
+    pub fn update(&mut self, seqn: u32, timestamp: Option<(u32, u32)>) {
+        if let Some((val, _)) = timestamp.filter(|_| self.enabled) {
+            let newer = self.recent_age.is_none() || util::le(self.recent, val);
+            if newer && util::le(seqn, self.last_ack_sent) {
+                self.recent = val;
+                self.recent_age = Some(time::Instant::now());
+            }
+        }
+    }
+
+    /// whether a TSval is newer than TS.Recent, None if there is nothing to compare.
+    pub fn is_newer(&self, timestamp: Option<(u32, u32)>) -> Option<bool> {
+        let (val, _) = timestamp.filter(|_| self.enabled && self.recent_age.is_some())?;
+        Some(util::lt(self.recent, val))
+    }
+
+    /// the round trip time measured by the TSecr of an ACK, RFC 7323 4.1
+    pub fn rtt(&self, timestamp: Option<(u32, u32)>) -> Option<time::Duration> {
+        let (_, ecr) = timestamp.filter(|_| self.enabled)?;
+        if ecr == 0 {
+            return None;
+        }
+        let ms = Self::now().wrapping_sub(ecr);
+        // an echo from the future is bogus
+        (ms < 1 << 31).then(|| time::Duration::from_millis(ms as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paws_rejects_old_timestamps() {
+        let mut timestamps = Timestamps::default();
+        // nothing to compare before the first TSval
+        assert!(!timestamps.is_old(Some((100, 0))));
+        timestamps.on_syn(Some((100, 0)));
+        assert!(!timestamps.is_old(Some((100, 0))));
+        assert!(!timestamps.is_old(Some((101, 0))));
+        assert!(timestamps.is_old(Some((99, 0))));
+        // a segment without the option, or after the option is off
+        assert!(!timestamps.is_old(None));
+        timestamps.enabled = false;
+        assert!(!timestamps.is_old(Some((99, 0))));
+    }
+
+    #[test]
+    fn ts_recent_follows_the_last_ack_sent() {
+        let mut timestamps = Timestamps::default();
+        timestamps.on_syn(Some((u32::MAX - 10, 0)));
+        timestamps.option(1000);
+        // the segment starts beyond the last acknowledgment we sent
+        timestamps.update(1001, Some((u32::MAX - 5, 0)));
+        assert_eq!(timestamps.recent, u32::MAX - 10);
+        // across the wraparound of TSval
+        timestamps.update(1000, Some((5, 0)));
+        assert_eq!(timestamps.recent, 5);
+        assert!(timestamps.is_old(Some((u32::MAX, 0))));
+        // an older TSval does not move TS.Recent back
+        timestamps.update(900, Some((3, 0)));
+        assert_eq!(timestamps.recent, 5);
+        assert_eq!(timestamps.is_newer(Some((6, 0))), Some(true));
+    }
+}
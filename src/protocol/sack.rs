@@ -0,0 +1,212 @@
+use crate::util;
+use std::collections::VecDeque;
+
+/// how many recent out-of-order arrivals are remembered for the order of the SACK blocks
+const MAX_RECENT: usize = 8;
+
+/// The receiver side of SACK, RFC 2018 section 4 and RFC 2883.
+#[derive(Default)]
+pub(crate) struct Receiver {
+    // the sequence numbers of the latest out-of-order segments, the most recent first
+    recent: VecDeque<u32>,
+    // a range which was received twice, reported once in the first block
+    dsack: Option<(u32, u32)>,
+}
+
+impl Receiver {
+    /// records the arrival of an out-of-order segment, its block will be reported first.
+    pub fn on_out_of_order(&mut self, seqn: u32) {
+        self.recent.retain(|&seq| seq != seqn);
+        self.recent.push_front(seqn);
+        self.recent.truncate(MAX_RECENT);
+    }
+
+    /// records a range of duplicate data.
+    pub fn on_duplicate(&mut self, start: u32, end: u32) {
+        if util::lt(start, end) {
+            self.dsack = Some((start, end));
+        }
+    }
+
+    /// builds at most `max` SACK blocks from the out-of-order `ranges`. A D-SACK block goes
+    /// first, followed by the range holding it, then the blocks of the most recent arrivals.
+    pub fn blocks(&mut self, ranges: &[(u32, u32)], max: usize) -> Vec<(u32, u32)> {
+        let containing = |seq: u32| {
+            ranges
+                .iter()
+                .copied()
+                .find(|&(start, end)| util::segment_valid(start, seq, end))
+        };
+        let mut blocks = vec![];
+        // the D-SACK block may repeat a range, it is not taken into account when deduplicating.
+        let skip = self.dsack.is_some() as usize;
+        if let Some((start, end)) = self.dsack.take() {
+            blocks.push((start, end));
+            if let Some(range) = containing(start) {
+                blocks.push(range);
+            }
+        }
+        self.recent.retain(|&seq| containing(seq).is_some());
+        for &seq in self.recent.iter() {
+            let range = containing(seq).unwrap();
+            if !blocks[skip..].contains(&range) {
+                blocks.push(range);
+            }
+        }
+        for &range in ranges.iter().rev() {
+            if !blocks[skip..].contains(&range) {
+                blocks.push(range);
+            }
+        }
+        blocks.truncate(max);
+        blocks
+    }
+}
+
+/// The sender side of SACK, which records the ranges above SND.UNA the peer holds.
+#[derive(Default)]
+pub(crate) struct Scoreboard {
+    // sorted and merged ranges, [start, end)
+    blocks: Vec<(u32, u32)>,
+    // the end of the latest retransmission in the current recovery
+    pub high_rxt: Option<u32>,
+}
+
+impl Scoreboard {
+    /// adds the SACK blocks of an ACK. The blocks outside (una, nxt], such as D-SACK blocks,
+    /// do not tell anything new and are ignored.
+    pub fn update(&mut self, una: u32, nxt: u32, blocks: &[(u32, u32)]) {
+        for &(start, end) in blocks {
+            if !util::lt(una, end) || !util::le(end, nxt) || !util::lt(start, end) {
+                continue;
+            }
+            let start = if util::lt(start, una) { una } else { start };
+            self.blocks.push((start, end));
+        }
+        self.blocks
+            .sort_by_key(|&(start, _)| start.wrapping_sub(una));
+        let mut merged: Vec<(u32, u32)> = Vec::with_capacity(self.blocks.len());
+        for &(start, end) in self.blocks.iter() {
+            match merged.last_mut() {
+                Some(last) if util::le(start, last.1) => {
+                    if util::lt(last.1, end) {
+                        last.1 = end;
+                    }
+                }
+                _ => merged.push((start, end)),
+            }
+        }
+        self.blocks = merged;
+    }
+
+    /// forgets the ranges below the new SND.UNA.
+    pub fn acknowledge(&mut self, una: u32) {
+        self.blocks.retain(|&(_, end)| util::lt(una, end));
+        if let Some(first) = self.blocks.first_mut() {
+            if util::lt(first.0, una) {
+                first.0 = una;
+            }
+        }
+    }
+
+    /// the end of the range containing `seq`, if the peer holds it.
+    pub fn held(&self, seq: u32) -> Option<u32> {
+        self.blocks
+            .iter()
+            .find(|&&(start, end)| util::segment_valid(start, seq, end))
+            .map(|&(_, end)| end)
+    }
+
+    /// the start of the first range after `seq`.
+    pub fn next_held(&self, seq: u32) -> Option<u32> {
+        self.blocks
+            .iter()
+            .find(|&&(start, _)| util::lt(seq, start))
+            .map(|&(start, _)| start)
+    }
+
+    /// the number of bytes in [from, to) the peer does not hold.
+    pub fn missing(&self, from: u32, to: u32) -> usize {
+        let held: u32 = self
+            .blocks
+            .iter()
+            .filter(|&&(start, end)| util::lt(start, to) && util::lt(from, end))
+            .map(|&(start, end)| {
+                let start = if util::lt(start, from) { from } else { start };
+                let end = if util::lt(to, end) { to } else { end };
+                end.wrapping_sub(start)
+            })
+            .sum();
+        to.wrapping_sub(from).saturating_sub(held) as usize
+    }
+
+    /// the highest sequence number the peer holds.
+    pub fn highest(&self) -> Option<u32> {
+        self.blocks.last().map(|&(_, end)| end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scoreboard_merges_blocks() {
+        let mut scoreboard = Scoreboard::default();
+        scoreboard.update(100, 1000, &[(300, 400), (200, 250)]);
+        assert_eq!(scoreboard.blocks, vec![(200, 250), (300, 400)]);
+        // overlapping, adjacent, and a block which covers the others
+        scoreboard.update(100, 1000, &[(240, 260), (400, 450)]);
+        assert_eq!(scoreboard.blocks, vec![(200, 260), (300, 450)]);
+        scoreboard.update(100, 1000, &[(150, 500)]);
+        assert_eq!(scoreboard.blocks, vec![(150, 500)]);
+        assert_eq!(scoreboard.held(499), Some(500));
+        assert_eq!(scoreboard.held(500), None);
+        assert_eq!(scoreboard.missing(100, 600), 150);
+        assert_eq!(scoreboard.missing(200, 400), 0);
+    }
+
+    #[test]
+    fn scoreboard_ignores_blocks_outside_the_flight() {
+        let mut scoreboard = Scoreboard::default();
+        // a D-SACK block below SND.UNA, and a block beyond SND.NXT
+        scoreboard.update(100, 1000, &[(50, 100), (900, 1100)]);
+        assert!(scoreboard.blocks.is_empty());
+        // a block which straddles SND.UNA is cut
+        scoreboard.update(100, 1000, &[(50, 150)]);
+        assert_eq!(scoreboard.blocks, vec![(100, 150)]);
+        scoreboard.acknowledge(120);
+        assert_eq!(scoreboard.blocks, vec![(120, 150)]);
+        scoreboard.acknowledge(150);
+        assert!(scoreboard.blocks.is_empty());
+    }
+
+    #[test]
+    fn scoreboard_merges_across_wraparound() {
+        let una = u32::MAX - 99;
+        let mut scoreboard = Scoreboard::default();
+        scoreboard.update(una, 1000, &[(10, 20), (u32::MAX - 9, 5)]);
+        assert_eq!(scoreboard.blocks, vec![(u32::MAX - 9, 5), (10, 20)]);
+        scoreboard.update(una, 1000, &[(5, 10)]);
+        assert_eq!(scoreboard.blocks, vec![(u32::MAX - 9, 20)]);
+        assert_eq!(scoreboard.highest(), Some(20));
+        assert_eq!(scoreboard.next_held(una), Some(u32::MAX - 9));
+    }
+
+    #[test]
+    fn receiver_reports_dsack_and_recent_blocks_first() {
+        let mut receiver = Receiver::default();
+        let ranges = [(100, 200), (300, 400), (500, 600)];
+        receiver.on_out_of_order(300);
+        receiver.on_duplicate(350, 360);
+        assert_eq!(
+            receiver.blocks(&ranges, 4),
+            vec![(350, 360), (300, 400), (500, 600), (100, 200)]
+        );
+        // the D-SACK block is reported once
+        assert_eq!(
+            receiver.blocks(&ranges, 3),
+            vec![(300, 400), (500, 600), (100, 200)]
+        );
+    }
+}
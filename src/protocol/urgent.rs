@@ -0,0 +1,148 @@
+use crate::util;
+use std::collections::VecDeque;
+
+/// The receiver side of urgent data, RFC 9293 3.8.5. The urgent pointer points to the byte
+/// following the urgent data, RFC 6093. Out of line, the last urgent byte is taken out of the
+/// stream, as BSD does.
+#[derive(Default)]
+pub(crate) struct Receiver {
+    /// the urgent bytes are left in the stream
+    pub inline: bool,
+    // the sequence number following the urgent data which has not arrived yet
+    up: Option<u32>,
+    // the latest urgent pointer which has been handled, later segments may repeat it
+    last: Option<u32>,
+    // the last urgent byte, taken out of the stream
+    oob: Option<u8>,
+    // the number of bytes in incoming in front of the last urgent byte
+    mark: Option<usize>,
+}
+
+impl Receiver {
+    /// records the urgent pointer of a segment starting at `seqn`.
+    pub fn on_pointer(&mut self, seqn: u32, pointer: u16, rcv_nxt: u32) {
+        let up = seqn.wrapping_add(pointer as u32);
+        if pointer == 0 || !util::lt(rcv_nxt, up) || self.last.is_some_and(|l| util::le(up, l)) {
+            return;
+        }
+        if self.up.is_none_or(|old| util::lt(old, up)) {
+            self.up = Some(up);
+        }
+    }
+
+    /// finds the urgent data among the bytes just moved into `incoming`, which ends at
+    /// `rcv_nxt`.
+    pub fn on_delivered(&mut self, incoming: &mut VecDeque<u8>, rcv_nxt: u32) {
+        let up = match self.up {
+            Some(up) if util::le(up, rcv_nxt) => up,
+            _ => return,
+        };
+        self.up = None;
+        self.last = Some(up);
+        let behind = rcv_nxt.wrapping_sub(up) as usize + 1;
+        let index = match incoming.len().checked_sub(behind) {
+            Some(index) => index,
+            None => return,
+        };
+        if !self.inline {
+            self.oob = incoming.remove(index);
+        }
+        self.mark = Some(index);
+    }
+
+    /// takes the out-of-band byte.
+    pub fn take(&mut self) -> Option<u8> {
+        self.oob.take()
+    }
+
+    /// whether the next byte to read is at the urgent mark.
+    pub fn at_mark(&self) -> bool {
+        self.mark == Some(0)
+    }
+
+    /// how many of `available` bytes a read may take, it stops at the mark.
+    pub fn readable(&self, available: usize) -> usize {
+        match self.mark {
+            Some(mark) if mark > 0 => std::cmp::min(mark, available),
+            _ => available,
+        }
+    }
+
+    /// the reader has taken `n` bytes in front of the stream.
+    pub fn on_read(&mut self, n: usize) {
+        self.mark = match self.mark {
+            Some(0) if n > 0 => None,
+            Some(mark) => Some(mark.saturating_sub(n)),
+            None => None,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn out_of_line_byte_is_taken_from_the_stream() {
+        let mut receiver = Receiver::default();
+        let mut incoming: VecDeque<u8> = b"abc".iter().copied().collect();
+        // a segment starting at 100 with 6 bytes, the urgent pointer points behind the 'U'
+        receiver.on_pointer(100, 5, 100);
+        incoming.extend(b"xyzwU!");
+        receiver.on_delivered(&mut incoming, 106);
+        assert_eq!(receiver.take(), Some(b'U'));
+        assert_eq!(
+            incoming,
+            b"abcxyzw!".iter().copied().collect::<VecDeque<u8>>()
+        );
+        // a read stops at the mark, which is in front of the byte following the urgent byte
+        assert_eq!(receiver.readable(incoming.len()), 7);
+        receiver.on_read(4);
+        assert!(!receiver.at_mark());
+        assert_eq!(receiver.readable(4), 3);
+        receiver.on_read(3);
+        assert!(receiver.at_mark());
+        receiver.on_read(1);
+        assert!(!receiver.at_mark());
+        assert_eq!(receiver.readable(10), 10);
+    }
+
+    #[test]
+    fn inline_byte_stays_in_the_stream() {
+        let mut receiver = Receiver {
+            inline: true,
+            ..Default::default()
+        };
+        let mut incoming = VecDeque::new();
+        receiver.on_pointer(0, 3, 0);
+        incoming.extend(b"abcd");
+        receiver.on_delivered(&mut incoming, 4);
+        assert_eq!(receiver.take(), None);
+        assert_eq!(incoming.len(), 4);
+        // the mark is at the urgent byte itself
+        assert_eq!(receiver.readable(4), 2);
+        receiver.on_read(2);
+        assert!(receiver.at_mark());
+    }
+
+    #[test]
+    fn pointer_waits_for_the_urgent_byte() {
+        let mut receiver = Receiver::default();
+        let mut incoming = VecDeque::new();
+        // the urgent byte is in a later segment, across the sequence wraparound
+        let seqn = u32::MAX - 1;
+        receiver.on_pointer(seqn, 4, seqn);
+        incoming.extend(b"ab");
+        receiver.on_delivered(&mut incoming, 0);
+        assert_eq!(receiver.take(), None);
+        incoming.extend(b"cU");
+        receiver.on_delivered(&mut incoming, 2);
+        assert_eq!(receiver.take(), Some(b'U'));
+        assert_eq!(receiver.readable(incoming.len()), 3);
+        // a retransmission repeating the old pointer is ignored
+        receiver.on_pointer(seqn, 4, 2);
+        incoming.extend(b"d");
+        receiver.on_delivered(&mut incoming, 3);
+        assert_eq!(receiver.take(), None);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::protocol;
+use crate::protocol::congestion;
 use log::{debug, info};
 use std::collections::{HashMap, VecDeque};
 use std::fmt::Debug;
@@ -6,6 +7,7 @@ use std::io;
 use std::io::{Read, Write};
 use std::net::Ipv4Addr;
 use std::sync::{Arc, Condvar, Mutex};
+use std::time;
 
 pub type Acm = Arc<AtomicallyConnectionManager>;
 #[derive(Default)]
@@ -20,10 +22,274 @@ pub struct SocketPair {
     pub dst: (Ipv4Addr, u16),
 }
 
+/// the dynamic port range of IANA, ephemeral ports of active opens are chosen from here.
+const EPHEMERAL_PORT_START: u16 = 49152;
+/// how long the reason of an aborted connection is kept for the streams which have not seen it
+const ABORTED_LIFETIME: time::Duration = time::Duration::from_secs(60);
+/// the most aborted connections whose reasons are kept
+const MAX_ABORTED: usize = 1024;
+
+/// When a listener answers SYNs with SYN cookies instead of keeping half-open connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SynCookies {
+    #[default]
+    Off,
+    /// when the number of half-open connections of the port reaches the threshold
+    Auto(usize),
+    Always,
+}
+
+/// What a listener does with a SYN or a final ACK which does not fit into its queues.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// ignores the segment, the peer will retransmit it
+    #[default]
+    Drop,
+    /// answers the segment with a RST
+    Reset,
+}
+
+/// The limits of the queues of a listener.
+#[derive(Debug, Clone, Copy)]
+pub struct Backlog {
+    /// the number of established connections waiting for accept()
+    pub accept_queue: usize,
+    /// the number of half-open connections in SYN-RECEIVED
+    pub syn_queue: usize,
+    pub overflow: Overflow,
+    /// how long a half-open connection may wait for the final ACK before it is aborted
+    pub syn_timeout: time::Duration,
+}
+
+impl Default for Backlog {
+    fn default() -> Self {
+        Self {
+            accept_queue: 128,
+            syn_queue: 256,
+            overflow: Overflow::Drop,
+            syn_timeout: time::Duration::from_secs(75),
+        }
+    }
+}
+
+/// The counters of a listener.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ListenerStats {
+    /// handshakes which completed while the accept queue was full
+    pub accept_overflows: u64,
+    /// SYNs which arrived while the SYN queue was full
+    pub syn_overflows: u64,
+    /// half-open connections aborted by the SYN timeout
+    pub syn_timeouts: u64,
+}
+
+/// How a SYN to a listener is answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// with a new half-open connection
+    Accept,
+    /// with a SYN cookie
+    Cookie,
+    /// by the overflow policy
+    Overflow(Overflow),
+}
+
+/// A bound port.
+#[derive(Default)]
+pub struct ListenerState {
+    // connections which are waiting for accept(), and when they arrived
+    pub pending: VecDeque<(SocketPair, time::Instant)>,
+    // the congestion control algorithm of accepted connections
+    pub congestion: congestion::Algorithm,
+    pub syn_cookies: SynCookies,
+    pub backlog: Backlog,
+    pub stats: ListenerStats,
+}
+
+/// The settings an interface gives to each of its new connections.
+#[derive(Clone, Copy)]
+pub struct ConnectionConfig {
+    // the maximum segment lifetime
+    pub msl: time::Duration,
+    // the initial size of the receive buffer
+    pub recv_buffer: usize,
+    // the size the receive buffer may grow to, None if it does not grow
+    pub recv_buffer_max: Option<usize>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            msl: protocol::DEFAULT_MSL,
+            recv_buffer: protocol::DEFAULT_RECV_BUFFER,
+            recv_buffer_max: Some(protocol::DEFAULT_RECV_BUFFER_MAX),
+        }
+    }
+}
+
+impl ConnectionConfig {
+    /// applies the settings to a new connection, which must be done before its SYN or SYN-ACK
+    /// is sent. The receive buffer is given to the constructor of the TCB already.
+    pub fn configure(&self, c: &mut protocol::TCB, algorithm: congestion::Algorithm) {
+        c.set_congestion(algorithm);
+        c.set_msl(self.msl);
+        c.set_recv_buffer_max(self.recv_buffer_max);
+    }
+}
+
 #[derive(Default)]
 pub struct ConnectionManager {
     pub connections: HashMap<SocketPair, protocol::TCB>,
-    pub pending: HashMap<u16, VecDeque<SocketPair>>,
+    pub listeners: HashMap<u16, ListenerState>,
+    // connections which were torn down by an error, and the reason of it.
+    // The entries expire, so the map does not grow without bound.
+    pub aborted: HashMap<SocketPair, (io::ErrorKind, time::Instant)>,
+    // offset of the next ephemeral port to try.
+    next_port: u16,
+    // the initial sequence numbers of the connections, keyed for this interface
+    pub iss: protocol::IssGenerator,
+    // the rate limit of the challenge ACKs of all the connections
+    pub challenge_acks: protocol::ChallengeAcks,
+    // the settings of new connections
+    pub config: ConnectionConfig,
+}
+
+impl ConnectionManager {
+    /// picks a local port for an active open to `remote`. A port is usable if no listener is bond
+    /// to it and it is not used by another connection to the same remote socket.
+    pub fn ephemeral_port(&mut self, local: Ipv4Addr, remote: (Ipv4Addr, u16)) -> Option<u16> {
+        let range = (u16::MAX - EPHEMERAL_PORT_START) as u32 + 1;
+        for i in 0..range {
+            let offset = (self.next_port as u32 + i) % range;
+            let port = EPHEMERAL_PORT_START + offset as u16;
+            let sp = SocketPair {
+                src: remote,
+                dst: (local, port),
+            };
+            if !self.listeners.contains_key(&port) && !self.connections.contains_key(&sp) {
+                self.next_port = ((offset + 1) % range) as u16;
+                return Some(port);
+            }
+        }
+        None
+    }
+
+    /// the numbers of half-open and established connections waiting on the listener of `port`.
+    pub fn queue_lens(&self, port: u16) -> (usize, usize) {
+        let listener = match self.listeners.get(&port) {
+            Some(l) => l,
+            None => return (0, 0),
+        };
+        listener
+            .pending
+            .iter()
+            .filter_map(|(sp, _)| self.connections.get(sp))
+            .fold((0, 0), |(half_open, estab), c| {
+                if c.is_connecting() {
+                    (half_open + 1, estab)
+                } else {
+                    (half_open, estab + 1)
+                }
+            })
+    }
+
+    /// decides how a SYN to `port` is answered, and counts it if it overflows. A full SYN queue
+    /// is bypassed by SYN cookies if they are not off, a full accept queue is not.
+    pub fn admit(&mut self, port: u16) -> Admission {
+        let (half_open, estab) = self.queue_lens(port);
+        let listener = match self.listeners.get_mut(&port) {
+            Some(l) => l,
+            None => return Admission::Accept,
+        };
+        if estab >= listener.backlog.accept_queue {
+            listener.stats.accept_overflows += 1;
+            return Admission::Overflow(listener.backlog.overflow);
+        }
+        let syn_full = half_open >= listener.backlog.syn_queue;
+        match listener.syn_cookies {
+            SynCookies::Always => Admission::Cookie,
+            SynCookies::Auto(threshold) if syn_full || half_open >= threshold => Admission::Cookie,
+            _ if syn_full => {
+                listener.stats.syn_overflows += 1;
+                Admission::Overflow(listener.backlog.overflow)
+            }
+            _ => Admission::Accept,
+        }
+    }
+
+    /// whether the accept queue of `port` has no room for another established connection.
+    pub fn accept_queue_full(&self, port: u16) -> bool {
+        let (_, estab) = self.queue_lens(port);
+        self.listeners
+            .get(&port)
+            .is_some_and(|l| estab >= l.backlog.accept_queue)
+    }
+
+    /// takes the half-open connections which have waited longer than the SYN timeout of their
+    /// listener. The connections which are gone are dropped from the queues as well.
+    pub fn expired_half_open(&mut self) -> Vec<SocketPair> {
+        let Self {
+            connections,
+            listeners,
+            ..
+        } = self;
+        let mut expired = vec![];
+        for listener in listeners.values_mut() {
+            let timeout = listener.backlog.syn_timeout;
+            let mut timeouts = 0;
+            listener
+                .pending
+                .retain(|(sp, since)| match connections.get(sp) {
+                    Some(c) if c.is_connecting() && since.elapsed() > timeout => {
+                        expired.push(*sp);
+                        timeouts += 1;
+                        false
+                    }
+                    Some(_) => true,
+                    None => false,
+                });
+            listener.stats.syn_timeouts += timeouts;
+        }
+        expired
+    }
+
+    /// takes the first established connection waiting on the listener of `port`.
+    fn take_established(&mut self, port: u16) -> Option<SocketPair> {
+        let listener = self.listeners.get_mut(&port)?;
+        let connections = &self.connections;
+        let i = listener
+            .pending
+            .iter()
+            .position(|(sp, _)| connections.get(sp).is_some_and(|c| !c.is_connecting()))?;
+        listener.pending.remove(i).map(|(sp, _)| sp)
+    }
+
+    /// the error for a stream whose connection is gone, which tells why it was aborted.
+    fn gone(&self, sp: &SocketPair, msg: &str) -> io::Error {
+        let kind = self
+            .aborted
+            .get(sp)
+            .map(|&(kind, _)| kind)
+            .unwrap_or(io::ErrorKind::ConnectionAborted);
+        io::Error::new(kind, msg)
+    }
+
+    /// removes a connection, keeping the reason if it was aborted by an error.
+    pub fn remove(&mut self, sp: &SocketPair) {
+        if let Some(c) = self.connections.remove(sp) {
+            if let Some(e) = c.error {
+                self.aborted
+                    .retain(|_, (_, at)| at.elapsed() < ABORTED_LIFETIME);
+                if self.aborted.len() >= MAX_ABORTED {
+                    let oldest = self.aborted.iter().min_by_key(|(_, (_, at))| *at);
+                    if let Some(oldest) = oldest.map(|(sp, _)| *sp) {
+                        self.aborted.remove(&oldest);
+                    }
+                }
+                self.aborted.insert(*sp, (e, time::Instant::now()));
+            }
+        }
+    }
 }
 
 pub struct TcpListener {
@@ -35,21 +301,46 @@ impl TcpListener {
     pub fn accept(&mut self) -> io::Result<TcpStream> {
         let mut cm = self.m.manager.lock().unwrap();
         loop {
-            cm = self.m.estab_notifier.wait(cm).unwrap();
-            if let Some(sp) = cm
-                .pending
-                .get_mut(&self.port)
-                .expect("port closed while listener still active")
-                .pop_front()
-            {
+            assert!(
+                cm.listeners.contains_key(&self.port),
+                "port closed while listener still active"
+            );
+            // connections may be queued already, a full accept queue notifies nobody.
+            if let Some(sp) = cm.take_established(self.port) {
                 debug!("Listener: Let's Streaming!!!");
                 return Ok(TcpStream {
                     socketpair: sp,
                     m: self.m.clone(),
                 });
             }
+            cm = self.m.estab_notifier.wait(cm).unwrap();
+        }
+    }
+
+    /// sets when SYN cookies are used for this port.
+    pub fn set_syn_cookies(&self, mode: SynCookies) {
+        let mut cm = self.m.manager.lock().unwrap();
+        if let Some(l) = cm.listeners.get_mut(&self.port) {
+            l.syn_cookies = mode;
+        }
+    }
+
+    /// sets the congestion control algorithm of the connections accepted from now on.
+    pub fn set_congestion(&self, algorithm: congestion::Algorithm) {
+        let mut cm = self.m.manager.lock().unwrap();
+        if let Some(l) = cm.listeners.get_mut(&self.port) {
+            l.congestion = algorithm;
         }
     }
+
+    /// the overflow and timeout counters of this port.
+    pub fn stats(&self) -> ListenerStats {
+        let cm = self.m.manager.lock().unwrap();
+        cm.listeners
+            .get(&self.port)
+            .map(|l| l.stats)
+            .unwrap_or_default()
+    }
 }
 
 #[derive(Clone)]
@@ -58,6 +349,12 @@ pub struct TcpStream {
     m: Arc<AtomicallyConnectionManager>,
 }
 
+impl TcpStream {
+    pub(crate) fn new(socketpair: SocketPair, m: Acm) -> Self {
+        Self { socketpair, m }
+    }
+}
+
 impl Read for TcpStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let mut cm = self
@@ -66,46 +363,35 @@ impl Read for TcpStream {
             .lock()
             .expect("failed to get lock in reading");
         loop {
-            cm = self.m.reading_notifier.wait(cm).unwrap();
-            // let c = cm.connections.get_mut(&self.socketpair).ok_or_else(|| {
-            //      io::Error::new(
-            //          io::ErrorKind::ConnectionAborted,
-            //          "stream terminated unexpectedly",
-            //      )
-            // })?;
-            if let Some(c) = cm.connections.get_mut(&self.socketpair) {
-                if c.closed && c.incoming.is_empty() {
-                    debug!("Stream::Read: Recv closed and incoming empty, ending...");
-                    return Ok(0);
-                }
-
-                if !c.incoming.is_empty() {
+            // queued bytes are returned before waiting: with a zero window nothing may arrive to
+            // wake us up until a read opens the window again.
+            match cm.connections.get_mut(&self.socketpair) {
+                Some(c) if !c.incoming.is_empty() => {
                     debug!("Stream::Read: start reading");
-                    // be careful for when reading from vecdeque.
-                    let mut nread = buf.len();
-                    let (head, tail) = c.incoming.as_slices();
-
-                    if nread < head.len() {
-                        buf[..].copy_from_slice(&head[..nread]);
-                    } else {
-                        let head_size = head.len();
-                        buf[..head_size].copy_from_slice(&head[..]);
-                        nread = head_size;
-                    }
-                    // NOTE: tail is empty because we NEVER call push_front().
-                    assert_eq!(true, tail.is_empty());
+                    // a read stops at the urgent mark.
+                    let nread = std::cmp::min(buf.len(), c.readable());
+                    // NOTE: taking the urgent byte out may leave incoming in two slices.
+                    let head = c.incoming.make_contiguous();
+                    buf[..nread].copy_from_slice(&head[..nread]);
 
                     //remember drop
-                    drop(c.incoming.drain(..nread));
+                    c.on_read(nread);
                     return Ok(nread);
-                } else {
-                    return Ok(1);
-                };
-            };
-
-            // NOTE: If the buf length is shorter than incoming queue, we MUST NOT run into wait
-            // until the incoming is fully read out or the left data will not be read until the
-            // next segment arrives.
+                }
+                Some(c) if c.closed => {
+                    debug!("Stream::Read: Recv closed and incoming empty, ending...");
+                    return Ok(0);
+                }
+                Some(_) => {}
+                // a connection which was closed normally is at its end
+                None => {
+                    if cm.aborted.contains_key(&self.socketpair) {
+                        return Err(cm.gone(&self.socketpair, "connection aborted"));
+                    }
+                    return Ok(0);
+                }
+            }
+            cm = self.m.reading_notifier.wait(cm).unwrap();
         }
     }
 }
@@ -117,62 +403,272 @@ impl Write for TcpStream {
     /// several
     /// TCP segments for this buffer.
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut m = self.m.manager.lock().unwrap();
-        let c = m.connections.get_mut(&self.socketpair).ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "Stream was terminated unexpectedly",
-            )
-        })?;
-        if c.closed {
-            return Err(io::Error::new(
-                io::ErrorKind::BrokenPipe,
-                "Stream write already closed",
-            ));
-        }
-        if c.outgoing.len() >= 1024 {
-            // TODO: block
-            return Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "too many bytes buffered",
-            ));
-        };
-        let write_len = std::cmp::min(buf.len(), 1024 - c.outgoing.len());
-        c.outgoing.extend(buf[..write_len].iter());
-        info!("Stream::Write: c.outgoing  {:?} bytes", c.outgoing.len());
-
-        Ok(write_len)
+        self.send(buf, false)
     }
     fn flush(&mut self) -> io::Result<()> {
-        let mut m = self.m.manager.lock().unwrap();
-        let c = m.connections.get_mut(&self.socketpair).ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "Connection was terminated unexpectedly",
-            )
-        })?;
-
-        if c.outgoing.is_empty() {
+        if self.with_connection(|c| c.outgoing.is_empty())? {
             Ok(())
         } else {
             // TODO: block
-            return Err(io::Error::new(
+            Err(io::Error::new(
                 io::ErrorKind::WouldBlock,
                 "too many bytes buffered",
-            ));
+            ))
         }
     }
 }
 impl TcpStream {
+    /// runs `f` on the connection of this stream, or tells why the connection is gone.
+    fn with_connection<T>(&self, f: impl FnOnce(&mut protocol::TCB) -> T) -> io::Result<T> {
+        let mut m = self.m.manager.lock().unwrap();
+        let m = &mut *m;
+        match m.connections.get_mut(&self.socketpair) {
+            Some(c) => Ok(f(c)),
+            None => Err(m.gone(&self.socketpair, "Connection was terminated unexpectedly")),
+        }
+    }
+
+    /// queues `buf` into outgoing, the queued data is marked urgent if `urgent` is set.
+    fn send(&self, buf: &[u8], urgent: bool) -> io::Result<usize> {
+        self.with_connection(|c| {
+            if c.closed {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "Stream write already closed",
+                ));
+            }
+            if c.send_space() == 0 {
+                // TODO: block
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "too many bytes buffered",
+                ));
+            };
+            let write_len = std::cmp::min(buf.len(), c.send_space());
+            c.outgoing.extend(buf[..write_len].iter());
+            if urgent {
+                c.mark_urgent();
+            }
+            info!("Stream::Write: c.outgoing  {:?} bytes", c.outgoing.len());
+
+            Ok(write_len)
+        })?
+    }
+
+    /// sends `buf` as urgent data, the peer is told where it ends. Returns how many bytes were
+    /// queued, like `write`.
+    pub fn send_urgent(&self, buf: &[u8]) -> io::Result<usize> {
+        self.send(buf, true)
+    }
+
+    /// takes the last byte of the urgent data received out of band, None if there is none.
+    pub fn read_urgent(&self) -> io::Result<Option<u8>> {
+        self.with_connection(|c| c.read_urgent())
+    }
+
+    /// whether the next byte `read` returns is at the urgent mark. A read never goes past the
+    /// mark, so the urgent data can be found in the stream.
+    pub fn at_urgent_mark(&self) -> io::Result<bool> {
+        self.with_connection(|c| c.at_urgent_mark())
+    }
+
+    /// leaves the urgent data in the stream, instead of taking its last byte out of band for
+    /// `read_urgent`.
+    pub fn set_urgent_inline(&self, inline: bool) -> io::Result<()> {
+        self.with_connection(|c| c.set_urgent_inline(inline))
+    }
+
+    /// returns a snapshot of the state of the connection, such as the current RTO.
+    pub fn stats(&self) -> io::Result<protocol::Stats> {
+        self.with_connection(|c| c.stats())
+    }
+
+    /// switches the congestion control algorithm of this connection.
+    pub fn set_congestion(&self, algorithm: congestion::Algorithm) -> io::Result<()> {
+        self.with_connection(|c| c.set_congestion(algorithm))
+    }
+
+    /// sets the retransmission thresholds R1 and R2 of RFC 1122: after `r1` retransmissions of
+    /// a segment `stats().trouble` is set, after `r2` the connection is aborted with `TimedOut`.
+    pub fn set_retries(&self, r1: u32, r2: u32) -> io::Result<()> {
+        self.with_connection(|c| c.set_retries(r1, r2))
+    }
+
+    /// sets how long sent data may stay unacknowledged before the connection is aborted with
+    /// `TimedOut`, None for no limit but R2.
+    pub fn set_user_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        self.with_connection(|c| c.set_user_timeout(timeout))
+    }
+
+    /// turns the keepalive on, or off with None. An idle connection is probed, and aborted with
+    /// `TimedOut` if the peer does not answer.
+    pub fn set_keepalive(&self, keepalive: Option<protocol::Keepalive>) -> io::Result<()> {
+        self.with_connection(|c| c.set_keepalive(keepalive))
+    }
+
+    /// sets the size of the receive buffer, which bounds the window offered to the peer. The
+    /// buffer is no longer auto-tuned afterwards.
+    pub fn set_recv_buffer(&self, size: usize) -> io::Result<()> {
+        self.with_connection(|c| {
+            c.set_recv_buffer(size);
+            c.set_recv_buffer_max(None);
+        })
+    }
+
+    /// sets the size of the send buffer, which holds the data written until it is acknowledged.
+    pub fn set_send_buffer(&self, size: usize) -> io::Result<()> {
+        self.with_connection(|c| c.set_send_buffer(size))
+    }
+
+    /// turns Nagle's algorithm off, so small writes are sent at once instead of being gathered
+    /// while data is in flight.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.with_connection(|c| c.set_nodelay(nodelay))
+    }
+
+    /// whether Nagle's algorithm is off.
+    pub fn nodelay(&self) -> io::Result<bool> {
+        self.with_connection(|c| c.nodelay())
+    }
+
+    /// turns delayed ACKs off, so every segment is acknowledged at once.
+    pub fn set_quickack(&self, quickack: bool) -> io::Result<()> {
+        self.with_connection(|c| c.set_quickack(quickack))
+    }
+
+    /// sets how long an ACK may wait for data to go along with, between 40 and 200 milliseconds.
+    pub fn set_ack_delay(&self, delay: time::Duration) -> io::Result<()> {
+        self.with_connection(|c| c.set_ack_delay(delay))
+    }
+
     pub fn shutdown(&self) -> io::Result<()> {
         info!("shutdown called");
-        let mut m = self.m.manager.lock().unwrap();
-        let c = m.connections.get_mut(&self.socketpair).ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "Connection was terminated unexpectedly",
-            )
-        })?;
-        c.close()
+        self.with_connection(|c| c.close())?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(port: u16) -> SocketPair {
+        SocketPair {
+            src: (Ipv4Addr::new(192, 168, 0, 1), port),
+            dst: (Ipv4Addr::new(192, 168, 0, 2), 80),
+        }
+    }
+
+    fn abort(cm: &mut ConnectionManager, sp: SocketPair, kind: io::ErrorKind) {
+        let mut c = protocol::TCB::connect(sp.dst, sp.src, 0, protocol::DEFAULT_RECV_BUFFER);
+        c.error = Some(kind);
+        cm.connections.insert(sp, c);
+        cm.remove(&sp);
+    }
+
+    /// a half-open connection of `sp` waiting on the listener of port 80.
+    fn half_open(cm: &mut ConnectionManager, sp: SocketPair, since: time::Instant) {
+        let c = protocol::TCB::connect(sp.dst, sp.src, 0, protocol::DEFAULT_RECV_BUFFER);
+        cm.connections.insert(sp, c);
+        let listener = cm.listeners.entry(80).or_default();
+        listener.pending.push_back((sp, since));
+    }
+
+    /// an established connection of `sp` waiting on the listener of port 80, which is rebuilt
+    /// from the ACK of a SYN cookie.
+    fn established(cm: &mut ConnectionManager, sp: SocketPair, since: time::Instant) {
+        let ip = etherparse::Ipv4Header::new(
+            0,
+            64,
+            etherparse::IpTrafficClass::Tcp,
+            sp.src.0.octets(),
+            sp.dst.0.octets(),
+        );
+        let mut tcp = etherparse::TcpHeader::new(sp.src.1, sp.dst.1, 1, 1024);
+        tcp.ack = true;
+        tcp.acknowledgment_number = 1;
+        let mut buf = vec![];
+        ip.write(&mut buf).unwrap();
+        tcp.write(&mut buf).unwrap();
+        let ip_header = etherparse::Ipv4HeaderSlice::from_slice(&buf).unwrap();
+        let tcp_header =
+            etherparse::TcpHeaderSlice::from_slice(&buf[ip_header.slice().len()..]).unwrap();
+        let c = protocol::TCB::from_cookie(
+            &ip_header,
+            &tcp_header,
+            1460,
+            protocol::DEFAULT_RECV_BUFFER,
+        );
+        cm.connections.insert(sp, c);
+        let listener = cm.listeners.entry(80).or_default();
+        listener.pending.push_back((sp, since));
+    }
+
+    #[test]
+    fn admit_by_the_queues() {
+        let mut cm = ConnectionManager::default();
+        assert_eq!(cm.admit(80), Admission::Accept);
+        let now = time::Instant::now();
+        half_open(&mut cm, pair(1), now);
+        let listener = cm.listeners.get_mut(&80).unwrap();
+        listener.backlog.syn_queue = 2;
+        listener.backlog.accept_queue = 1;
+        listener.backlog.overflow = Overflow::Reset;
+        assert_eq!(cm.admit(80), Admission::Accept);
+        half_open(&mut cm, pair(2), now);
+        assert_eq!(cm.queue_lens(80), (2, 0));
+        assert_eq!(cm.admit(80), Admission::Overflow(Overflow::Reset));
+        // SYN cookies take over a full SYN queue, or from their threshold on
+        cm.listeners.get_mut(&80).unwrap().syn_cookies = SynCookies::Auto(10);
+        assert_eq!(cm.admit(80), Admission::Cookie);
+        cm.listeners.get_mut(&80).unwrap().syn_cookies = SynCookies::Auto(1);
+        cm.listeners.get_mut(&80).unwrap().backlog.syn_queue = 10;
+        assert_eq!(cm.admit(80), Admission::Cookie);
+        // but not a full accept queue
+        established(&mut cm, pair(3), now);
+        assert!(cm.accept_queue_full(80));
+        assert_eq!(cm.admit(80), Admission::Overflow(Overflow::Reset));
+        let stats = &cm.listeners[&80].stats;
+        assert_eq!((stats.syn_overflows, stats.accept_overflows), (1, 1));
+    }
+
+    #[test]
+    fn half_open_connections_expire() {
+        let mut cm = ConnectionManager::default();
+        let now = time::Instant::now();
+        let old = now - time::Duration::from_secs(2);
+        half_open(&mut cm, pair(1), old);
+        half_open(&mut cm, pair(2), now);
+        established(&mut cm, pair(3), old);
+        half_open(&mut cm, pair(4), old);
+        cm.connections.remove(&pair(4));
+        cm.listeners.get_mut(&80).unwrap().backlog.syn_timeout = time::Duration::from_secs(1);
+        assert_eq!(cm.expired_half_open(), vec![pair(1)]);
+        let listener = &cm.listeners[&80];
+        // the connection which is gone leaves the queue, and is not counted as a timeout
+        let pending: Vec<_> = listener.pending.iter().map(|&(sp, _)| sp).collect();
+        assert_eq!(pending, vec![pair(2), pair(3)]);
+        assert_eq!(listener.stats.syn_timeouts, 1);
+    }
+
+    #[test]
+    fn aborted_reasons_are_bounded() {
+        let mut cm = ConnectionManager::default();
+        abort(&mut cm, pair(1), io::ErrorKind::TimedOut);
+        assert_eq!(cm.gone(&pair(1), "").kind(), io::ErrorKind::TimedOut);
+        for port in 2..MAX_ABORTED as u16 + 10 {
+            abort(&mut cm, pair(port), io::ErrorKind::ConnectionReset);
+        }
+        assert_eq!(cm.aborted.len(), MAX_ABORTED);
+        // the oldest reason goes first
+        assert_eq!(
+            cm.gone(&pair(1), "").kind(),
+            io::ErrorKind::ConnectionAborted
+        );
+        // expired reasons are dropped at the next abort
+        for (_, at) in cm.aborted.values_mut() {
+            *at -= ABORTED_LIFETIME;
+        }
+        abort(&mut cm, pair(1), io::ErrorKind::TimedOut);
+        assert_eq!(cm.aborted.len(), 1);
     }
 }